@@ -0,0 +1,473 @@
+use std::io;
+use std::fs;
+use std::path::{Path, PathBuf};
+use std::io::{Read, Write, BufReader, BufWriter};
+use std::sync::{Mutex, RwLock};
+use std::collections::BTreeMap;
+
+use crate::index::{self, BatchOp, IndexStore};
+
+const OP_PUT: u8 = 1;
+const OP_DELETE: u8 = 2;
+
+// Once the log grows past this size it is folded into a fresh snapshot.
+const COMPACT_THRESHOLD: u64 = 64 * 1024 * 1024;
+
+// Keys and records are short; anything claiming to be larger is a torn header.
+const MAX_ENTRY: usize = 64 * 1024 * 1024;
+
+// Snapshots are written in entries of at most this many operations.
+const SNAPSHOT_CHUNK: usize = 4096;
+
+// Durable key -> record index. Every batch is appended to a write-ahead log
+// and synced before returning; the log is periodically compacted into a
+// snapshot. On open the snapshot is loaded and the log replayed on top of it,
+// discarding a torn entry at the tail left behind by a crash.
+//
+// Layout of the database directory:
+//   LOCK      - held with flock by the one process that has the database open
+//   snapshot  - full copy of the index at the last compaction
+//   wal       - batches written since the last compaction
+//
+// Both files are a sequence of entries, one per batch:
+//   [payload len: u32][payload][crc32 of payload: u32]
+// where the payload is a sequence of operations:
+//   [op: u8][key len: u32][value len: u32][key][value]
+//
+// Lookups only take `map`. Writers go through `wal`, which is held across the
+// sync, and apply a batch to `map` only once it is durable; so reads never
+// wait on the disk. Writers take `wal` before `map`.
+pub struct LogStore {
+	map: RwLock<BTreeMap<String, String>>,
+	wal: Mutex<Wal>,
+	_lock: fs::File,
+}
+
+struct Wal {
+	dir: PathBuf,
+	file: fs::File,
+	size: u64,
+}
+
+impl LogStore {
+	pub fn open<P: AsRef<Path>>(dir: P) -> io::Result<Self> {
+		let dir = dir.as_ref().to_path_buf();
+		fs::create_dir_all(&dir)?;
+
+		// Two processes on one database would each keep their own index and
+		// truncate the log under each other, so like LevelDB only one gets in.
+		// The lock goes away with the file, even if the process dies.
+		let lock = fs::OpenOptions::new().create(true).truncate(false).write(true).open(dir.join("LOCK"))?;
+		match lock.try_lock() {
+			Ok(()) => {},
+			Err(fs::TryLockError::WouldBlock) => {
+				return Err(io::Error::new(io::ErrorKind::WouldBlock, format!("database {} is in use by another process", dir.display())));
+			},
+			Err(fs::TryLockError::Error(e)) => return Err(e),
+		}
+
+		let mut map = BTreeMap::new();
+
+		let snapshot = dir.join("snapshot");
+		if snapshot.exists() {
+			replay(&snapshot, &mut map)?;
+		}
+
+		let wal_path = dir.join("wal");
+		let valid = if wal_path.exists() { replay(&wal_path, &mut map)? } else { 0 };
+
+		let file = fs::OpenOptions::new().create(true).append(true).open(&wal_path)?;
+
+		// Drop whatever follows the last complete entry so new writes are not
+		// appended after garbage.
+		file.set_len(valid)?;
+		file.sync_all()?;
+
+		let mut wal = Wal { dir, file, size: valid };
+
+		if wal.size > COMPACT_THRESHOLD {
+			wal.compact(&map)?;
+		}
+
+		Ok(Self { map: RwLock::new(map), wal: Mutex::new(wal), _lock: lock })
+	}
+}
+
+impl IndexStore for LogStore {
+	fn get(&self, key: &str) -> Option<String> {
+		self.map.read().unwrap().get(key).cloned()
+	}
+
+	fn write_batch(&self, batch: Vec<BatchOp>) -> io::Result<()> {
+		let mut wal = self.wal.lock().unwrap();
+
+		let mut payload = Vec::new();
+		for op in batch.iter() {
+			match op {
+				BatchOp::Put(k, v) => encode_op(&mut payload, OP_PUT, k, v),
+				BatchOp::Delete(k) => encode_op(&mut payload, OP_DELETE, k, ""),
+			}
+		}
+
+		wal.append(&payload)?;
+		index::apply(&mut self.map.write().unwrap(), batch);
+
+		// The batch is durable already, a failed compaction is retried with
+		// the next one.
+		if wal.size > COMPACT_THRESHOLD {
+			if let Err(e) = wal.compact(&self.map.read().unwrap()) {
+				eprintln!("db: compaction failed: {}", e);
+			}
+		}
+
+		Ok(())
+	}
+
+	fn scan(&self, prefix: &str, start: &str, limit: usize) -> Vec<(String, String)> {
+		index::scan(&self.map.read().unwrap(), prefix, start, limit)
+	}
+
+	fn clear(&self) -> io::Result<()> {
+		let mut wal = self.wal.lock().unwrap();
+		let mut map = self.map.write().unwrap();
+		map.clear();
+		wal.compact(&map)
+	}
+}
+
+impl Wal {
+	fn append(&mut self, payload: &[u8]) -> io::Result<()> {
+		let entry = frame(payload);
+
+		if let Err(e) = self.file.write_all(&entry).and_then(|_| self.file.sync_data()) {
+			self.cut_off();
+			return Err(e);
+		}
+
+		self.size += entry.len() as u64;
+
+		Ok(())
+	}
+
+	// A write that fails halfway is cut off again, or replay would stop at the
+	// torn entry and lose every batch appended after it.
+	fn cut_off(&mut self) {
+		if let Err(e) = self.file.set_len(self.size) {
+			eprintln!("db: cannot cut off a failed write: {}", e);
+		}
+	}
+
+	// Writes the whole map into a new snapshot, atomically swaps it in and
+	// then empties the log. A crash between the two steps only means the log
+	// is replayed over a snapshot that already contains it, which is harmless.
+	fn compact(&mut self, map: &BTreeMap<String, String>) -> io::Result<()> {
+		let tmp = self.dir.join("snapshot.tmp");
+
+		{
+			let mut w = BufWriter::new(fs::File::create(&tmp)?);
+			let entries = map.iter().collect::<Vec<(&String, &String)>>();
+
+			for chunk in entries.chunks(SNAPSHOT_CHUNK) {
+				let mut payload = Vec::new();
+				for (k, v) in chunk {
+					encode_op(&mut payload, OP_PUT, k, v);
+				}
+				w.write_all(&frame(&payload))?;
+			}
+
+			w.into_inner().map_err(|e| e.into_error())?.sync_all()?;
+		}
+
+		fs::rename(&tmp, self.dir.join("snapshot"))?;
+		fs::File::open(&self.dir)?.sync_all()?;
+
+		self.file.set_len(0)?;
+		self.file.sync_all()?;
+		self.size = 0;
+
+		Ok(())
+	}
+}
+
+fn encode_op(buf: &mut Vec<u8>, op: u8, key: &str, value: &str) {
+	buf.push(op);
+	buf.extend_from_slice(&(key.len() as u32).to_le_bytes());
+	buf.extend_from_slice(&(value.len() as u32).to_le_bytes());
+	buf.extend_from_slice(key.as_bytes());
+	buf.extend_from_slice(value.as_bytes());
+}
+
+fn frame(payload: &[u8]) -> Vec<u8> {
+	let mut buf = Vec::with_capacity(payload.len() + 8);
+
+	buf.extend_from_slice(&(payload.len() as u32).to_le_bytes());
+	buf.extend_from_slice(payload);
+	buf.extend_from_slice(&crc32fast::hash(payload).to_le_bytes());
+
+	buf
+}
+
+// Decodes the operations of one entry, `None` if the payload is malformed.
+fn decode_ops(mut payload: &[u8]) -> Option<Vec<BatchOp>> {
+	let mut ops = Vec::new();
+
+	while !payload.is_empty() {
+		if payload.len() < 9 { return None; }
+
+		let klen = u32::from_le_bytes([payload[1], payload[2], payload[3], payload[4]]) as usize;
+		let vlen = u32::from_le_bytes([payload[5], payload[6], payload[7], payload[8]]) as usize;
+
+		if payload.len() < 9 + klen + vlen { return None; }
+
+		let key = String::from_utf8(payload[9..9 + klen].to_vec()).ok()?;
+		let value = String::from_utf8(payload[9 + klen..9 + klen + vlen].to_vec()).ok()?;
+
+		match payload[0] {
+			OP_PUT => ops.push(BatchOp::Put(key, value)),
+			OP_DELETE => ops.push(BatchOp::Delete(key)),
+			_ => return None,
+		}
+
+		payload = &payload[9 + klen + vlen..];
+	}
+
+	Some(ops)
+}
+
+// Applies every complete entry in `path` to `map` and returns the length of
+// the valid prefix of the file. Replay stops at the first short or corrupt entry.
+fn replay(path: &Path, map: &mut BTreeMap<String, String>) -> io::Result<u64> {
+	let mut r = BufReader::new(fs::File::open(path)?);
+	let mut valid = 0u64;
+
+	loop {
+		let mut len = [0u8; 4];
+		if !read_full(&mut r, &mut len)? { break; }
+
+		let len = u32::from_le_bytes(len) as usize;
+		if len > MAX_ENTRY { break; }
+
+		let mut body = vec![0u8; len + 4];
+		if !read_full(&mut r, &mut body)? { break; }
+
+		let (payload, crc) = body.split_at(len);
+		if crc32fast::hash(payload).to_le_bytes() != crc {
+			eprintln!("db: corrupt entry in {} at offset {}, ignoring the rest", path.display(), valid);
+			break;
+		}
+
+		match decode_ops(payload) {
+			Some(ops) => index::apply(map, ops),
+			None => break,
+		}
+
+		valid += (body.len() + 4) as u64;
+	}
+
+	Ok(valid)
+}
+
+// Like `read_exact`, but reports a clean or torn end of file as `false`.
+fn read_full<R: Read>(r: &mut R, buf: &mut [u8]) -> io::Result<bool> {
+	let mut read = 0;
+
+	while read < buf.len() {
+		match r.read(&mut buf[read..]) {
+			Ok(0) => return Ok(false),
+			Ok(n) => read += n,
+			Err(ref e) if e.kind() == io::ErrorKind::Interrupted => {},
+			Err(e) => return Err(e),
+		}
+	}
+
+	Ok(true)
+}
+
+#[cfg(test)]
+mod tests {
+	use super::*;
+
+	// A directory of its own for each test, gone again once it is done.
+	struct TempDir(PathBuf);
+
+	impl TempDir {
+		fn new(name: &str) -> Self {
+			let dir = std::env::temp_dir().join(format!("mkv-db-{}-{}", std::process::id(), name));
+			let _ = fs::remove_dir_all(&dir);
+			Self(dir)
+		}
+
+		fn wal_len(&self) -> u64 {
+			fs::metadata(self.0.join("wal")).unwrap().len()
+		}
+
+		fn append_to_wal(&self, bytes: &[u8]) {
+			fs::OpenOptions::new().append(true).open(self.0.join("wal")).unwrap().write_all(bytes).unwrap();
+		}
+	}
+
+	impl Drop for TempDir {
+		fn drop(&mut self) {
+			let _ = fs::remove_dir_all(&self.0);
+		}
+	}
+
+	fn put_entry(key: &str, value: &str) -> Vec<u8> {
+		let mut payload = Vec::new();
+		encode_op(&mut payload, OP_PUT, key, value);
+		frame(&payload)
+	}
+
+	#[test]
+	fn log_is_replayed_on_open() {
+		let dir = TempDir::new("replay");
+
+		{
+			let db = LogStore::open(&dir.0).unwrap();
+			db.put("a", "1".to_string()).unwrap();
+			db.write_batch(vec![BatchOp::Put("b".to_string(), "2".to_string()), BatchOp::Delete("a".to_string())]).unwrap();
+		}
+
+		let db = LogStore::open(&dir.0).unwrap();
+		assert_eq!(db.get("a"), None);
+		assert_eq!(db.get("b"), Some("2".to_string()));
+	}
+
+	#[test]
+	fn torn_tail_is_cut_off_on_open() {
+		let dir = TempDir::new("torn");
+
+		LogStore::open(&dir.0).unwrap().put("a", "1".to_string()).unwrap();
+		let valid = dir.wal_len();
+
+		// Half of an entry, as left by a crash in the middle of a write.
+		let entry = put_entry("b", "2");
+		dir.append_to_wal(&entry[..entry.len() - 3]);
+
+		{
+			let db = LogStore::open(&dir.0).unwrap();
+			assert_eq!(db.get("a"), Some("1".to_string()));
+			assert_eq!(db.get("b"), None);
+			assert_eq!(dir.wal_len(), valid);
+
+			db.put("c", "3".to_string()).unwrap();
+		}
+
+		// What was written after the cut is not stuck behind garbage.
+		let db = LogStore::open(&dir.0).unwrap();
+		assert_eq!(db.get("a"), Some("1".to_string()));
+		assert_eq!(db.get("c"), Some("3".to_string()));
+	}
+
+	#[test]
+	fn header_claiming_a_huge_entry_is_torn() {
+		let dir = TempDir::new("huge");
+
+		LogStore::open(&dir.0).unwrap().put("a", "1".to_string()).unwrap();
+		let valid = dir.wal_len();
+		dir.append_to_wal(&(MAX_ENTRY as u32 + 1).to_le_bytes());
+
+		let db = LogStore::open(&dir.0).unwrap();
+		assert_eq!(db.get("a"), Some("1".to_string()));
+		assert_eq!(dir.wal_len(), valid);
+	}
+
+	#[test]
+	fn entry_failing_its_crc_ends_the_log() {
+		let dir = TempDir::new("crc");
+
+		{
+			let db = LogStore::open(&dir.0).unwrap();
+			db.put("a", "1".to_string()).unwrap();
+		}
+
+		let valid = dir.wal_len();
+
+		{
+			let db = LogStore::open(&dir.0).unwrap();
+			db.put("b", "2".to_string()).unwrap();
+			db.put("c", "3".to_string()).unwrap();
+		}
+
+		// Flip a bit in the value of `b`, past its length and op header.
+		let mut bytes = fs::read(dir.0.join("wal")).unwrap();
+		bytes[valid as usize + 4 + 9 + 1] ^= 1;
+		fs::write(dir.0.join("wal"), bytes).unwrap();
+
+		let db = LogStore::open(&dir.0).unwrap();
+		assert_eq!(db.get("a"), Some("1".to_string()));
+		assert_eq!(db.get("b"), None);
+		assert_eq!(db.get("c"), None);
+		assert_eq!(dir.wal_len(), valid);
+	}
+
+	#[test]
+	fn failed_append_is_left_out() {
+		let dir = TempDir::new("append");
+
+		{
+			let db = LogStore::open(&dir.0).unwrap();
+			db.put("a", "1".to_string()).unwrap();
+			let valid = dir.wal_len();
+
+			// What a write that failed halfway left behind is cut off again.
+			dir.append_to_wal(b"torn");
+			db.wal.lock().unwrap().cut_off();
+			assert_eq!(dir.wal_len(), valid);
+
+			// A write that fails keeps the batch out of the index.
+			db.wal.lock().unwrap().file = fs::File::open(dir.0.join("wal")).unwrap();
+			assert!(db.put("b", "2".to_string()).is_err());
+			assert_eq!(db.get("b"), None);
+			assert_eq!(dir.wal_len(), valid);
+
+			db.wal.lock().unwrap().file = fs::OpenOptions::new().append(true).open(dir.0.join("wal")).unwrap();
+			db.put("c", "3".to_string()).unwrap();
+		}
+
+		let db = LogStore::open(&dir.0).unwrap();
+		assert_eq!(db.get("a"), Some("1".to_string()));
+		assert_eq!(db.get("b"), None);
+		assert_eq!(db.get("c"), Some("3".to_string()));
+	}
+
+	#[test]
+	fn log_is_replayed_over_the_snapshot() {
+		let dir = TempDir::new("compact");
+
+		{
+			let db = LogStore::open(&dir.0).unwrap();
+			db.put("a", "1".to_string()).unwrap();
+			db.put("b", "2".to_string()).unwrap();
+
+			let mut wal = db.wal.lock().unwrap();
+			wal.compact(&db.map.read().unwrap()).unwrap();
+			drop(wal);
+
+			assert_eq!(dir.wal_len(), 0);
+
+			db.put("c", "3".to_string()).unwrap();
+			db.delete("a").unwrap();
+		}
+
+		{
+			let db = LogStore::open(&dir.0).unwrap();
+			assert_eq!(db.scan("", "", 0), vec![("b".to_string(), "2".to_string()), ("c".to_string(), "3".to_string())]);
+
+			db.clear().unwrap();
+		}
+
+		assert_eq!(LogStore::open(&dir.0).unwrap().scan("", "", 0), vec![]);
+	}
+
+	#[test]
+	fn second_open_is_refused() {
+		let dir = TempDir::new("lock");
+
+		let db = LogStore::open(&dir.0).unwrap();
+		assert_eq!(LogStore::open(&dir.0).err().map(|e| e.kind()), Some(io::ErrorKind::WouldBlock));
+
+		drop(db);
+		assert!(LogStore::open(&dir.0).is_ok());
+	}
+}
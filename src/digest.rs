@@ -0,0 +1,234 @@
+use std::io::{self, Read};
+use std::str::FromStr;
+
+use sha2::{Digest as _, Sha256};
+use xxhash_rust::xxh3::Xxh3;
+
+// Content hash used to check the integrity of stored blobs.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum Algorithm {
+	Md5,
+	Sha256,
+	Blake3,
+	Xxh3,
+}
+
+impl Algorithm {
+	pub const ALL: [Algorithm; 4] = [Algorithm::Md5, Algorithm::Sha256, Algorithm::Blake3, Algorithm::Xxh3];
+
+	pub fn name(self) -> &'static str {
+		match self {
+			Algorithm::Md5 => "md5",
+			Algorithm::Sha256 => "sha256",
+			Algorithm::Blake3 => "blake3",
+			Algorithm::Xxh3 => "xxh3",
+		}
+	}
+
+	// Length of the digest in bytes.
+	pub fn size(self) -> usize {
+		match self {
+			Algorithm::Md5 => 16,
+			Algorithm::Sha256 => 32,
+			Algorithm::Blake3 => 32,
+			Algorithm::Xxh3 => 16, // the 128 bit variant
+		}
+	}
+
+	pub fn hasher(self) -> Hasher {
+		match self {
+			Algorithm::Md5 => Hasher::Md5(md5::Context::new()),
+			Algorithm::Sha256 => Hasher::Sha256(Sha256::new()),
+			Algorithm::Blake3 => Hasher::Blake3(Box::default()),
+			Algorithm::Xxh3 => Hasher::Xxh3(Box::default()),
+		}
+	}
+}
+
+impl FromStr for Algorithm {
+	type Err = String;
+
+	fn from_str(s: &str) -> Result<Self, Self::Err> {
+		Algorithm::ALL.iter()
+			.find(|a| a.name() == s)
+			.copied()
+			.ok_or_else(|| format!("unknown hash algorithm {}", s))
+	}
+}
+
+pub enum Hasher {
+	Md5(md5::Context),
+	Sha256(Sha256),
+	Blake3(Box<blake3::Hasher>),
+	Xxh3(Box<Xxh3>),
+}
+
+impl Hasher {
+	pub fn update(&mut self, data: &[u8]) {
+		match self {
+			Hasher::Md5(h) => h.consume(data),
+			Hasher::Sha256(h) => h.update(data),
+			Hasher::Blake3(h) => { h.update(data); },
+			Hasher::Xxh3(h) => h.update(data),
+		}
+	}
+
+	pub fn finish(self) -> Digest {
+		match self {
+			Hasher::Md5(h) => Digest { algorithm: Algorithm::Md5, bytes: h.compute().0.to_vec() },
+			Hasher::Sha256(h) => Digest { algorithm: Algorithm::Sha256, bytes: h.finalize().to_vec() },
+			Hasher::Blake3(h) => Digest { algorithm: Algorithm::Blake3, bytes: h.finalize().as_bytes().to_vec() },
+			Hasher::Xxh3(h) => Digest { algorithm: Algorithm::Xxh3, bytes: h.digest128().to_be_bytes().to_vec() },
+		}
+	}
+}
+
+#[derive(Clone, Debug, PartialEq)]
+pub struct Digest {
+	pub algorithm: Algorithm,
+	pub bytes: Vec<u8>,
+}
+
+impl Digest {
+	pub fn hex(&self) -> String {
+		self.bytes.iter().map(|b| format!("{:02x}", b)).collect()
+	}
+
+	// How the digest is stored in a record: `<algorithm>:<hex>`, except for
+	// MD5 which keeps the bare hex of the original record format.
+	pub fn encode(&self) -> String {
+		match self.algorithm {
+			Algorithm::Md5 => self.hex(),
+			_ => format!("{}:{}", self.algorithm.name(), self.hex()),
+		}
+	}
+
+	// Reads a digest written by `encode` off the front of `s`, returns it
+	// along with the number of bytes it took up.
+	pub fn decode(s: &str) -> Option<(Digest, usize)> {
+		let (algorithm, skip) = Algorithm::ALL.iter()
+			.find(|a| s.starts_with(a.name()) && s[a.name().len()..].starts_with(':'))
+			.map(|a| (*a, a.name().len() + 1))
+			.unwrap_or((Algorithm::Md5, 0));
+
+		let end = skip + algorithm.size() * 2;
+		let hex = s.as_bytes().get(skip..end)?;
+
+		if !hex.iter().all(|b| b.is_ascii_hexdigit()) { return None; }
+
+		let bytes = hex.chunks(2)
+			.map(|c| u8::from_str_radix(std::str::from_utf8(c).unwrap(), 16).unwrap())
+			.collect();
+
+		Some((Digest { algorithm, bytes }, end))
+	}
+
+	// Response header announcing the digest, Content-MD5 (RFC 1864) for MD5
+	// and Digest (RFC 3230) for everything else.
+	pub fn header(&self) -> (&'static str, String) {
+		let encoded = base64::encode(&self.bytes);
+
+		match self.algorithm {
+			Algorithm::Md5 => ("Content-MD5", encoded),
+			Algorithm::Sha256 => ("Digest", format!("sha-256={}", encoded)),
+			a => ("Digest", format!("{}={}", a.name(), encoded)),
+		}
+	}
+}
+
+// Feeds everything read through it into a set of hashers.
+pub struct DigestReader<R: Read> {
+	inner: R,
+	hashers: Vec<Hasher>,
+}
+
+impl<R: Read> DigestReader<R> {
+	pub fn new(inner: R, algorithms: &[Algorithm]) -> Self {
+		Self { inner, hashers: algorithms.iter().map(|a| a.hasher()).collect() }
+	}
+
+	// Digests of everything read so far, in the order the algorithms were given.
+	pub fn finish(self) -> Vec<Digest> {
+		self.hashers.into_iter().map(Hasher::finish).collect()
+	}
+}
+
+impl<R: Read> Read for DigestReader<R> {
+	fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+		let n = self.inner.read(buf)?;
+
+		for h in self.hashers.iter_mut() {
+			h.update(&buf[..n]);
+		}
+
+		Ok(n)
+	}
+}
+
+#[cfg(test)]
+mod tests {
+	use super::*;
+
+	fn digest_of(algorithm: Algorithm, data: &[u8]) -> Digest {
+		let mut hasher = algorithm.hasher();
+		hasher.update(data);
+		hasher.finish()
+	}
+
+	#[test]
+	fn known_digests() {
+		assert_eq!(digest_of(Algorithm::Md5, b"hello").hex(), "5d41402abc4b2a76b9719d911017c592");
+		assert_eq!(digest_of(Algorithm::Sha256, b"hello").hex(), "2cf24dba5fb0a30e26e83b2ac5b9e29e1b161e5c1fa7425e73043362938b9824");
+
+		for a in Algorithm::ALL.iter() {
+			assert_eq!(digest_of(*a, b"hello").bytes.len(), a.size());
+		}
+	}
+
+	#[test]
+	fn md5_is_stored_as_bare_hex() {
+		let digest = digest_of(Algorithm::Md5, b"hello");
+		assert_eq!(digest.encode(), "5d41402abc4b2a76b9719d911017c592");
+
+		// As written by the original, followed by the volumes.
+		let (decoded, len) = Digest::decode("5d41402abc4b2a76b9719d911017c592localhost:3001").unwrap();
+		assert_eq!(decoded, digest);
+		assert_eq!(len, 32);
+	}
+
+	#[test]
+	fn tagged_digests_round_trip() {
+		for a in Algorithm::ALL.iter().filter(|a| **a != Algorithm::Md5) {
+			let digest = digest_of(*a, b"hello");
+			let encoded = digest.encode();
+			assert_eq!(encoded, format!("{}:{}", a.name(), digest.hex()));
+
+			let (decoded, len) = Digest::decode(&format!("{}localhost:3001", encoded)).unwrap();
+			assert_eq!(decoded, digest);
+			assert_eq!(len, encoded.len());
+		}
+	}
+
+	#[test]
+	fn malformed_digests_are_refused() {
+		assert_eq!(Digest::decode("5d41402abc"), None);
+		assert_eq!(Digest::decode("zz41402abc4b2a76b9719d911017c592"), None);
+		assert_eq!(Digest::decode("sha256:2cf24dba"), None);
+	}
+
+	#[test]
+	fn headers() {
+		assert_eq!(digest_of(Algorithm::Md5, b"hello").header(), ("Content-MD5", "XUFAKrxLKna5cZ2REBfFkg==".to_string()));
+		assert_eq!(digest_of(Algorithm::Sha256, b"hello").header(), ("Digest", "sha-256=LPJNul+wow4m6DsqxbninhsWHlwfp0JecwQzYpOLmCQ=".to_string()));
+		assert!(digest_of(Algorithm::Blake3, b"hello").header().1.starts_with("blake3="));
+	}
+
+	#[test]
+	fn algorithm_names() {
+		for a in Algorithm::ALL.iter() {
+			assert_eq!(a.name().parse::<Algorithm>(), Ok(*a));
+		}
+
+		assert!("crc32".parse::<Algorithm>().is_err());
+	}
+}
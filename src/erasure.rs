@@ -0,0 +1,501 @@
+use std::error::Error;
+use std::io::{self, Read};
+use std::str::FromStr;
+use std::sync::Arc;
+
+use reed_solomon_erasure::galois_8::ReedSolomon;
+use reqwest::blocking::Response;
+
+use crate::hash::key_to_path;
+use crate::remote::{remote_open, remote_open_range, remote_put_each, PutResult};
+
+// Largest piece of a single shard encoded at once.
+const CHUNK_SIZE: usize = 64 * 1024;
+
+// Reed-Solomon parameters from `--erasure`, written `data,parity`. A blob
+// is split into `data` shards plus `parity` shards computed from them, and
+// can be read back from any `data` of those.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct Codec {
+	pub data: usize,
+	pub parity: usize,
+}
+
+impl Codec {
+	pub fn shards(self) -> usize {
+		self.data + self.parity
+	}
+
+	fn rs(self) -> ReedSolomon {
+		ReedSolomon::new(self.data, self.parity).expect("erasure: invalid codec")
+	}
+}
+
+impl FromStr for Codec {
+	type Err = String;
+
+	fn from_str(s: &str) -> Result<Self, Self::Err> {
+		let parts = s.split(',').map(|x| x.parse::<usize>()).collect::<Vec<_>>();
+
+		let (data, parity) = match parts.as_slice() {
+			[Ok(data), Ok(parity)] => (*data, *parity),
+			_ => return Err(format!("erasure codec {} is not DATA,PARITY", s)),
+		};
+
+		if data == 0 || parity == 0 || data + parity > 256 {
+			return Err(format!("erasure codec {} is out of range", s));
+		}
+
+		Ok(Self { data, parity })
+	}
+}
+
+// How a blob was cut up. The blob is read in stripes of `codec.data` chunks of
+// `chunk` bytes each, the last one zero padded, and every stripe adds one
+// chunk to each shard. Shard `i` is stored on the `i`th volume of the record.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct Layout {
+	pub codec: Codec,
+	pub chunk: usize,
+	pub size: u64,
+}
+
+impl Layout {
+	// Small blobs get smaller chunks so padding doesn't blow them up.
+	pub fn new(codec: Codec, length: Option<u64>) -> Self {
+		let chunk = match length {
+			Some(length) => (length.div_ceil(codec.data as u64) as usize).clamp(1, CHUNK_SIZE),
+			None => CHUNK_SIZE,
+		};
+
+		Self { codec, chunk, size: 0 }
+	}
+
+	fn stripe(self) -> u64 {
+		(self.codec.data * self.chunk) as u64
+	}
+
+	pub fn shard_size(self) -> u64 {
+		self.size.div_ceil(self.stripe()) * self.chunk as u64
+	}
+
+	// How the layout is stored in a record: `ERASURE<data>,<parity>,<chunk>,<size>|`.
+	pub fn encode(&self) -> String {
+		format!("ERASURE{},{},{},{}|", self.codec.data, self.codec.parity, self.chunk, self.size)
+	}
+
+	// Reads a layout written by `encode` off the front of `s`, returns it along
+	// with the number of bytes it took up.
+	pub fn decode(s: &str) -> Option<(Layout, usize)> {
+		let rest = s.strip_prefix("ERASURE")?;
+		let end = rest.find('|')?;
+
+		let parts = rest[..end].split(',').map(|x| x.parse::<u64>().ok()).collect::<Option<Vec<u64>>>()?;
+		if parts.len() != 4 { return None; }
+
+		let codec = Codec { data: parts[0] as usize, parity: parts[1] as usize };
+		if codec.data == 0 || parts[2] == 0 { return None; }
+
+		Some((Layout { codec, chunk: parts[2] as usize, size: parts[3] }, "ERASURE".len() + end + 1))
+	}
+}
+
+// Where shard `i` of `key` lives on `volume`.
+pub fn shard_url(volume: &str, key: &str, i: usize) -> String {
+	format!("http://{}{}.{}", volume, key_to_path(key), i)
+}
+
+// Where the layout and digest of `key` are kept next to its shard on `volume`,
+// for `rebuild` to read back. Shard names can't hold them, the size of a blob
+// is only known once it has been written.
+pub fn layout_url(volume: &str, key: &str) -> String {
+	format!("http://{}{}.layout", volume, key_to_path(key))
+}
+
+// Encodes `body` and streams shard `i` to `remotes[i]`, with the same
+// bounded memory as `remote_put_all`. Returns the result of every upload
+// and the layout, which has the size of the body once it was read through.
+pub fn put_shards(remotes: &[String], codec: Codec, length: Option<u64>, body: &mut dyn Read) -> (Vec<PutResult>, Layout) {
+	let mut layout = Layout::new(codec, length);
+	let shard_length = length.map(|size| Layout { size, ..layout }.shard_size());
+
+	let all = (0..codec.shards()).collect::<Vec<usize>>();
+	let results = encode_into(remotes, &all, &mut layout, shard_length, body);
+
+	(results, layout)
+}
+
+// Writes the shards numbered in `missing` again, decoding the blob from the
+// others. `urls` is where every shard of the blob belongs.
+pub fn repair_shards(urls: &[String], missing: &[usize], layout: Layout) -> io::Result<Vec<PutResult>> {
+	let mut available = urls.to_vec();
+	for &i in missing {
+		available[i] = String::new();
+	}
+
+	let mut body = ShardReader::open(&available, layout)?;
+
+	let targets = missing.iter().map(|&i| urls[i].clone()).collect::<Vec<String>>();
+	let mut rewritten = Layout { size: 0, ..layout };
+
+	Ok(encode_into(&targets, missing, &mut rewritten, Some(layout.shard_size()), &mut body))
+}
+
+// Streams shard `shards[j]` of `body` to `remotes[j]`, counting the size of
+// the body into `layout`.
+fn encode_into(remotes: &[String], shards: &[usize], layout: &mut Layout, length: Option<u64>, body: &mut dyn Read) -> Vec<PutResult> {
+	let rs = layout.codec.rs();
+	let mut done = false;
+
+	remote_put_each(remotes, length, || {
+		if done { return Ok(None); }
+
+		let mut stripe = vec![0u8; layout.stripe() as usize];
+		let n = read_full(body, &mut stripe)?;
+
+		if n < stripe.len() { done = true; }
+		if n == 0 { return Ok(None); }
+
+		layout.size += n as u64;
+
+		let mut pieces = stripe.chunks(layout.chunk).map(|c| c.to_vec()).collect::<Vec<Vec<u8>>>();
+		pieces.resize(layout.codec.shards(), vec![0u8; layout.chunk]);
+
+		rs.encode(&mut pieces).map_err(|e| io::Error::other(format!("{:?}", e)))?;
+
+		Ok(Some(shards.iter().map(|&i| Arc::new(std::mem::take(&mut pieces[i]))).collect()))
+	})
+}
+
+// Reads a blob back from its shards, `urls[i]` being where shard `i` is and
+// empty if it is not known. Only `codec.data` shards are fetched, data shards
+// first so nothing has to be decoded while they are all there.
+pub struct ShardReader {
+	rs: ReedSolomon,
+	layout: Layout,
+	shards: Vec<Option<Response>>,
+	buf: Vec<u8>,
+	pos: usize,
+	remaining: u64,
+	degraded: bool,
+}
+
+impl ShardReader {
+	pub fn open(urls: &[String], layout: Layout) -> io::Result<Self> {
+		Self::open_with(urls, layout, remote_open)
+	}
+
+	// Reads bytes `[start, end)` of the blob only. Stripe `k` is at `k * chunk`
+	// in every shard, so just the stripes the range lies in are fetched and
+	// only the part of the first one before `start` is thrown away.
+	pub fn open_range(urls: &[String], layout: Layout, start: u64, end: u64) -> io::Result<Self> {
+		let stripe = layout.stripe();
+		let (first, last) = (start / stripe, end.div_ceil(stripe));
+		let chunk = layout.chunk as u64;
+
+		let mut reader = Self::open_with(urls, layout, |url| remote_open_range(url, first * chunk, last * chunk))?;
+		reader.remaining = end - first * stripe;
+
+		io::copy(&mut (&mut reader).take(start - first * stripe), &mut io::sink())?;
+		Ok(reader)
+	}
+
+	fn open_with<F>(urls: &[String], layout: Layout, open_shard: F) -> io::Result<Self>
+		where F: Fn(&str) -> Result<Response, Box<dyn Error>>
+	{
+		let mut shards = Vec::with_capacity(urls.len());
+		let mut open = 0;
+		let mut degraded = false;
+
+		for url in urls {
+			if url.is_empty() { degraded = true; }
+
+			if open == layout.codec.data || url.is_empty() {
+				shards.push(None);
+				continue;
+			}
+
+			match open_shard(url) {
+				Ok(resp) => {
+					shards.push(Some(resp));
+					open += 1;
+				},
+				Err(e) => {
+					eprintln!("erasure: cannot open shard {}: {}", url, e);
+					shards.push(None);
+					degraded = true;
+				},
+			}
+		}
+
+		if open < layout.codec.data {
+			return Err(io::Error::new(io::ErrorKind::NotFound, "not enough shards left to decode"));
+		}
+
+		Ok(Self { rs: layout.codec.rs(), layout, shards, buf: Vec::new(), pos: 0, remaining: layout.size, degraded })
+	}
+
+	// True if a shard turned out to be missing while opening.
+	pub fn degraded(&self) -> bool {
+		self.degraded
+	}
+
+	fn next_stripe(&mut self) -> io::Result<()> {
+		let chunk = self.layout.chunk;
+
+		let mut pieces = Vec::with_capacity(self.shards.len());
+		for shard in self.shards.iter_mut() {
+			match shard {
+				Some(resp) => {
+					let mut piece = vec![0u8; chunk];
+					resp.read_exact(&mut piece)?;
+					pieces.push(Some(piece));
+				},
+				None => pieces.push(None),
+			}
+		}
+
+		if pieces[..self.layout.codec.data].iter().any(Option::is_none) {
+			self.rs.reconstruct_data(&mut pieces).map_err(|e| io::Error::new(io::ErrorKind::InvalidData, format!("{:?}", e)))?;
+		}
+
+		self.buf = pieces.into_iter().take(self.layout.codec.data).flat_map(Option::unwrap).collect();
+		self.buf.truncate(self.remaining.min(self.buf.len() as u64) as usize);
+		self.remaining -= self.buf.len() as u64;
+		self.pos = 0;
+
+		Ok(())
+	}
+}
+
+impl Read for ShardReader {
+	fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+		if self.pos == self.buf.len() {
+			if self.remaining == 0 { return Ok(0); }
+			self.next_stripe()?;
+		}
+
+		let n = buf.len().min(self.buf.len() - self.pos);
+		buf[..n].copy_from_slice(&self.buf[self.pos..self.pos + n]);
+		self.pos += n;
+
+		Ok(n)
+	}
+}
+
+// Every shard of a blob, parity included, read a stripe at a time so they
+// can be checked against each other. `urls[i]` empty or unreadable is a
+// missing shard, and so is one that ends early from there on.
+pub struct Stripes {
+	layout: Layout,
+	shards: Vec<Option<Response>>,
+	remaining: u64,
+}
+
+impl Stripes {
+	pub fn open(urls: &[String], layout: Layout) -> Self {
+		let shards = urls.iter()
+			.map(|u| if u.is_empty() { None } else { remote_open(u).ok() })
+			.collect();
+
+		Self { layout, shards, remaining: layout.size }
+	}
+
+	// Which shards could be opened.
+	pub fn present(&self) -> Vec<bool> {
+		self.shards.iter().map(Option::is_some).collect()
+	}
+
+	// The piece of every shard in the next stripe and how many bytes of the
+	// blob it holds, `None` once the blob is read through.
+	pub fn next_stripe(&mut self) -> Option<(Vec<Option<Vec<u8>>>, usize)> {
+		if self.remaining == 0 { return None; }
+
+		let n = self.remaining.min(self.layout.stripe());
+		self.remaining -= n;
+
+		let chunk = self.layout.chunk;
+		let pieces = self.shards.iter_mut().map(|shard| {
+			let mut piece = vec![0u8; chunk];
+			let read = shard.as_mut().map(|resp| resp.read_exact(&mut piece).is_ok()).unwrap_or(false);
+
+			if !read { *shard = None; }
+			Some(piece).filter(|_| read)
+		}).collect();
+
+		Some((pieces, n as usize))
+	}
+}
+
+// What every shard of a stripe should hold going by the pieces numbered in
+// `from` alone, `codec.data` of them: decoded and encoded again.
+pub fn reencode(codec: Codec, pieces: &[Option<Vec<u8>>], from: &[usize]) -> io::Result<Vec<Vec<u8>>> {
+	let mut all = (0..pieces.len())
+		.map(|i| if from.contains(&i) { pieces[i].clone() } else { None })
+		.collect::<Vec<Option<Vec<u8>>>>();
+
+	codec.rs().reconstruct(&mut all).map_err(|e| io::Error::new(io::ErrorKind::InvalidData, format!("{:?}", e)))?;
+
+	Ok(all.into_iter().map(Option::unwrap).collect())
+}
+
+// Fills as much of `buf` as the reader has left, returns how much that was.
+fn read_full(r: &mut dyn Read, buf: &mut [u8]) -> io::Result<usize> {
+	let mut read = 0;
+
+	while read < buf.len() {
+		match r.read(&mut buf[read..]) {
+			Ok(0) => break,
+			Ok(n) => read += n,
+			Err(ref e) if e.kind() == io::ErrorKind::Interrupted => {},
+			Err(e) => return Err(e),
+		}
+	}
+
+	Ok(read)
+}
+
+#[cfg(test)]
+mod tests {
+	use super::*;
+	use std::sync::atomic::Ordering;
+	use crate::stub::{Stubs, StubVolume};
+
+	const CODEC: Codec = Codec { data: 4, parity: 2 };
+
+	// Not a multiple of anything in particular.
+	fn blob(size: usize) -> Vec<u8> {
+		(0..size).map(|i| (i * 7 + i / 251) as u8).collect()
+	}
+
+	fn urls(vol: &StubVolume, key: &str) -> Vec<String> {
+		(0..CODEC.shards()).map(|i| shard_url(&vol.addr, key, i)).collect()
+	}
+
+	fn read_back(urls: &[String], layout: Layout) -> io::Result<Vec<u8>> {
+		let mut body = Vec::new();
+		ShardReader::open(urls, layout)?.read_to_end(&mut body)?;
+		Ok(body)
+	}
+
+	#[test]
+	fn codec_bounds() {
+		assert_eq!("4,2".parse::<Codec>(), Ok(CODEC));
+		assert_eq!("255,1".parse::<Codec>(), Ok(Codec { data: 255, parity: 1 }));
+
+		for bad in ["0,2", "4,0", "255,2", "4", "4,2,1", "a,b", ""].iter() {
+			assert!(bad.parse::<Codec>().is_err(), "{}", bad);
+		}
+	}
+
+	#[test]
+	fn layouts_round_trip() {
+		let layout = Layout { codec: CODEC, chunk: 16384, size: 100001 };
+		let encoded = layout.encode();
+		assert_eq!(encoded, "ERASURE4,2,16384,100001|");
+		assert_eq!(Layout::decode(&format!("{}a:1,b:1", encoded)), Some((layout, encoded.len())));
+
+		for bad in ["ERASURE4,2,16384|", "ERASURE4,2,16384,1", "ERASURE0,2,1,1|", "ERASURE4,2,0,1|", "ERASUREx,2,1,1|", "a:1,b:1"].iter() {
+			assert_eq!(Layout::decode(bad), None, "{}", bad);
+		}
+	}
+
+	#[test]
+	fn chunks_and_shard_sizes() {
+		assert_eq!(Layout::new(CODEC, Some(10)).chunk, 3);
+		assert_eq!(Layout::new(CODEC, Some(12)).chunk, 3);
+		assert_eq!(Layout::new(CODEC, Some(0)).chunk, 1);
+		assert_eq!(Layout::new(CODEC, Some(1 << 30)).chunk, CHUNK_SIZE);
+		assert_eq!(Layout::new(CODEC, None).chunk, CHUNK_SIZE);
+
+		let shard_size = |chunk, size| Layout { codec: CODEC, chunk, size }.shard_size();
+		assert_eq!(shard_size(3, 0), 0);
+		assert_eq!(shard_size(3, 1), 3);
+		assert_eq!(shard_size(3, 12), 3);
+		assert_eq!(shard_size(3, 13), 6);
+		assert_eq!(shard_size(3, 24), 6);
+	}
+
+	#[test]
+	fn blobs_read_back_whole_and_degraded() {
+		let stubs = Arc::new(Stubs::default());
+		let vol = stubs.volume("v0");
+
+		for (n, &size) in [0, 1, 5, 12, 13, 1000, 3 * CHUNK_SIZE * CODEC.data + 7].iter().enumerate() {
+			// With a known length and streamed without one.
+			for &length in [Some(size as u64), None].iter() {
+				let key = format!("/{}-{}", n, length.is_some());
+				let urls = urls(&vol, &key);
+				let data = blob(size);
+
+				let (results, layout) = put_shards(&urls, CODEC, length, &mut &data[..]);
+				assert!(results.iter().all(Result::is_ok));
+				assert_eq!(layout.size, size as u64);
+
+				for url in urls.iter() {
+					let path = &url[url.find(&key_to_path(&key)).unwrap()..];
+					assert_eq!(vol.blobs.lock().unwrap()[path].len() as u64, layout.shard_size(), "{} {:?}", size, length);
+				}
+
+				assert_eq!(read_back(&urls, layout).unwrap(), data);
+
+				// Any `data` of the shards will do, be they unknown or gone.
+				let mut degraded = urls.clone();
+				degraded[0] = String::new();
+				degraded[2] = format!("{}.gone", urls[2]);
+				assert_eq!(read_back(&degraded, layout).unwrap(), data);
+
+				degraded[3] = String::new();
+				assert!(read_back(&degraded, layout).is_err());
+			}
+		}
+	}
+
+	#[test]
+	fn ranges_read_only_their_stripes() {
+		let stubs = Arc::new(Stubs::default());
+		let vol = stubs.volume("v0");
+		let urls = urls(&vol, "/a");
+
+		// Four stripes, the last one short.
+		let data = blob(3 * CHUNK_SIZE * CODEC.data + 7);
+		let (_, layout) = put_shards(&urls, CODEC, Some(data.len() as u64), &mut &data[..]);
+		let stripe = layout.stripe() as usize;
+
+		let read_range = |urls: &[String], start: usize, end: usize| {
+			let mut body = Vec::new();
+			ShardReader::open_range(urls, layout, start as u64, end as u64).unwrap().read_to_end(&mut body).unwrap();
+			body
+		};
+
+		let mut degraded = urls.clone();
+		degraded[1] = String::new();
+
+		for &(start, end) in [(0, 1), (0, data.len()), (5, stripe), (stripe, stripe + 1), (stripe - 1, 2 * stripe + 1), (data.len() - 1, data.len())].iter() {
+			assert_eq!(read_range(&urls, start, end), &data[start..end], "{}-{}", start, end);
+			assert_eq!(read_range(&degraded, start, end), &data[start..end], "{}-{} degraded", start, end);
+		}
+
+		// Only the one stripe a range at the end lies in is fetched.
+		vol.sent.store(0, Ordering::SeqCst);
+		read_range(&urls, data.len() - 3, data.len());
+		assert_eq!(vol.sent.load(Ordering::SeqCst), (CODEC.data * layout.chunk) as u64);
+	}
+
+	#[test]
+	fn repaired_shards_are_the_same() {
+		let stubs = Arc::new(Stubs::default());
+		let vol = stubs.volume("v0");
+		let urls = urls(&vol, "/a");
+		let data = blob(100000);
+
+		let (_, layout) = put_shards(&urls, CODEC, Some(data.len() as u64), &mut &data[..]);
+		let before = vol.blobs.lock().unwrap().clone();
+
+		vol.blobs.lock().unwrap().retain(|path, _| !path.ends_with(".1") && !path.ends_with(".4"));
+
+		let results = repair_shards(&urls, &[1, 4], layout).unwrap();
+		assert!(results.iter().all(Result::is_ok));
+		assert_eq!(*vol.blobs.lock().unwrap(), before);
+	}
+}
@@ -1,55 +1,198 @@
 use std::str;
+use std::str::FromStr;
+use std::cmp::Ordering;
+use std::collections::HashSet;
 
+// Same layout as the original: two levels of directories from the MD5 of the
+// key, then the key itself in base64 so `rebuild` can read it back.
 pub fn key_to_path(key: &str) -> String {
 	let digest = md5::compute(key.as_bytes());
-	
-	let mut buf = Vec::new();
-	buf.resize(digest.len() * 4 / 3 + 4, 0);
-	
-	let bytes_written = base64::encode_config_slice(digest.0, base64::STANDARD, &mut buf);
-	
-	buf.resize(bytes_written, 0);
-
-	format!("/{}/{}/{}", digest.0[0], digest.0[1], std::str::from_utf8(&buf).unwrap())
+
+	format!("/{:02x}/{:02x}/{}", digest.0[0], digest.0[1], base64::encode(key.as_bytes()))
+}
+
+// A volume server from `--volumes`, written `host:port[=weight][@domain]`.
+// A volume with twice the weight of another gets about twice as many keys.
+// Volumes sharing a failure domain (a rack, a zone) are kept from holding more
+// than one replica of a key whenever there are enough domains; volumes without
+// one are each their own domain.
+#[derive(Clone, Debug)]
+pub struct Volume {
+	pub addr: String,
+	pub weight: f64,
+	pub domain: String,
 }
 
+impl FromStr for Volume {
+	type Err = String;
+
+	fn from_str(s: &str) -> Result<Self, Self::Err> {
+		let (s, domain) = match s.find('@') {
+			Some(i) => (&s[..i], s[i + 1..].to_string()),
+			None => (s, String::new()),
+		};
+
+		let mut parts = s.splitn(2, '=');
+		let addr = parts.next().unwrap().to_string();
+
+		let weight = match parts.next() {
+			Some(w) => w.parse::<f64>().map_err(|e| format!("bad weight for volume {}: {}", addr, e))?,
+			None => 1.0,
+		};
+
+		if !(weight > 0.0 && weight.is_finite()) {
+			return Err(format!("weight for volume {} must be positive", addr));
+		}
+
+		let domain = if domain.is_empty() { addr.clone() } else { domain };
+
+		Ok(Self { addr, weight, domain })
+	}
+}
 
-#[derive(Eq, Ord, PartialEq, PartialOrd)]
-struct SortVol(Vec<u8>, String);
+struct SortVol(f64, Vec<u8>, String, String);
 
-pub fn key_to_volume(key: &str, volumes: &[String], _count: i32, svcount: i32) -> Vec<String> {
+// Picks the `count` volumes a key lives on, best first, by weighted rendezvous
+// hashing. Each volume draws an exponentially distributed score from
+// md5(key + volume) scaled down by its weight, and the lowest scores win, so a
+// volume is picked in proportion to its weight. Placement only depends on the
+// volume names and weights: adding a volume or changing its weight only moves
+// keys to or from that volume.
+//
+// Replicas go to distinct failure domains first, in score order; only when
+// there are fewer domains than replicas do domains get a second one.
+//
+// With equal weights and no domains the order is the same as sorting by the
+// raw digest, which is what the original does.
+pub fn key_to_volume(key: &str, volumes: &[Volume], count: i32, svcount: i32) -> Vec<String> {
 	let mut sortvols = Vec::<SortVol>::new();
 
 	volumes.iter().for_each(|x| {
-		let digest = md5::compute([key.as_bytes(), x.as_bytes()].concat());
-		sortvols.push(SortVol(digest.0.to_vec(), x.to_string()));
+		let digest = md5::compute([key.as_bytes(), x.addr.as_bytes()].concat());
+
+		// Top 53 bits of the digest as a uniform number in (0, 1).
+		let top = u64::from_be_bytes([digest.0[0], digest.0[1], digest.0[2], digest.0[3], digest.0[4], digest.0[5], digest.0[6], digest.0[7]]);
+		let u = ((top >> 11) as f64 + 0.5) / (1u64 << 53) as f64;
+
+		sortvols.push(SortVol(-(1.0 - u).ln() / x.weight, digest.0.to_vec(), x.addr.clone(), x.domain.clone()));
 	});
 
-	sortvols.sort();
+	sortvols.sort_by(|a, b| a.0.partial_cmp(&b.0).unwrap_or(Ordering::Equal).then_with(|| (&a.1, &a.2).cmp(&(&b.1, &b.2))));
+
+	let count = (count.max(0) as usize).min(sortvols.len());
+
+	let mut picked = vec![false; sortvols.len()];
+	let mut domains = HashSet::new();
+	let mut n = 0;
+
+	for (i, sv) in sortvols.iter().enumerate() {
+		if n < count && domains.insert(&sv.3) {
+			picked[i] = true;
+			n += 1;
+		}
+	}
+
+	for p in picked.iter_mut() {
+		if n < count && !*p {
+			*p = true;
+			n += 1;
+		}
+	}
 
 	let mut ret = Vec::<String>::new();
 
-	sortvols.iter().for_each(|sv| {
+	sortvols.iter().zip(picked).filter(|(_, p)| *p).for_each(|(sv, _)| {
 		if svcount == 1 {
-			ret.push(sv.1.clone());
+			ret.push(sv.2.clone());
 		} else {
-			let svhash = sv.0[12] << 24 + sv.0[13] << 16 + sv.0[14] << 8 + sv.0[15];
-			ret.push(format!("{}/sv{:>2}", sv.1.clone(), svhash % svcount as u8))
+			// The low dword of the score picks the subvolume, a single byte would
+			// spread keys unevenly. Names match the original, e.g. `sv0A`.
+			let svhash = u32::from_be_bytes([sv.1[12], sv.1[13], sv.1[14], sv.1[15]]);
+			ret.push(format!("{}/sv{:02X}", sv.2.clone(), svhash % svcount as u32))
 		}
 	});
 
 	ret
 }
 
-pub fn needs_rebalance(volumes: &[String], kvolumes: &[String]) -> bool {
+// True unless a key is stored on exactly the volumes `key_to_volume` picks for
+// it, in the same order, and those are spread over the failure domains.
+pub fn needs_rebalance(volumes: &[String], kvolumes: &[String], all: &[Volume]) -> bool {
+	if violates_spread(volumes, all) { return true; }
+
 	let vlen = volumes.len();
 	let klen = kvolumes.len();
 
 	if vlen != klen { return true; };
 
 	for i in 0..vlen {
-		if volumes[i] == kvolumes[i] { return true; }
+		if volumes[i] != kvolumes[i] { return true; }
 	}
 
 	false
-}
\ No newline at end of file
+}
+
+// True if replicas share a failure domain while another domain was available.
+// Replicas are given as stored in records, with their subvolume if any.
+pub fn violates_spread(rvolumes: &[String], all: &[Volume]) -> bool {
+	let domain_of = |rv: &String| {
+		let addr = rv.split('/').next().unwrap();
+		all.iter().find(|v| v.addr == addr).map(|v| v.domain.clone()).unwrap_or_else(|| addr.to_string())
+	};
+
+	let used = rvolumes.iter().map(domain_of).collect::<HashSet<String>>();
+	let available = all.iter().map(|v| &v.domain).collect::<HashSet<&String>>().len();
+
+	used.len() < rvolumes.len().min(available)
+}
+
+#[cfg(test)]
+mod tests {
+	use super::*;
+
+	// Expectations worked out with the original's key2volume and key2path.
+	fn volumes() -> Vec<Volume> {
+		["larry", "moe", "curly"].iter().map(|v| v.parse::<Volume>().unwrap()).collect()
+	}
+
+	#[test]
+	fn placement_matches_original() {
+		let tests = [
+			("hello", ["moe/sv03", "curly/sv03", "larry/sv09"]),
+			("world", ["larry/sv0E", "curly/sv10", "moe/sv0C"]),
+			("blah", ["moe/sv0F", "larry/sv00", "curly/sv12"]),
+			("helloworld", ["moe/sv0E", "larry/sv05", "curly/sv05"]),
+		];
+
+		for (key, expected) in tests.iter() {
+			assert_eq!(key_to_volume(key, &volumes(), 3, 20), expected, "{}", key);
+		}
+	}
+
+	#[test]
+	fn placement_without_subvolumes() {
+		assert_eq!(key_to_volume("hello", &volumes(), 1, 1), ["moe"]);
+		assert_eq!(key_to_volume("world", &volumes(), 1, 1), ["larry"]);
+		assert_eq!(key_to_volume("blah", &volumes(), 2, 1), ["moe", "larry"]);
+	}
+
+	#[test]
+	fn subvolume_from_big_endian_low_dword() {
+		// Little endian would give sv0A and the last byte alone sv13.
+		assert_eq!(key_to_volume("blah", &volumes(), 1, 20), ["moe/sv0F"]);
+		// And sv18 or sv52 here.
+		assert_eq!(key_to_volume("world", &volumes(), 1, 100), ["larry/sv36"]);
+	}
+
+	#[test]
+	fn subvolume_names_are_zero_padded_upper_hex() {
+		assert_eq!(key_to_volume("a", &volumes(), 2, 20), ["curly/sv01", "moe/sv01"]);
+		assert_eq!(key_to_volume("hello", &volumes(), 2, 256), ["moe/sv1F", "curly/svDF"]);
+	}
+
+	#[test]
+	fn path_matches_original() {
+		assert_eq!(key_to_path("hello"), "/5d/41/aGVsbG8=");
+		assert_eq!(key_to_path("/a/b"), "/ae/e3/L2EvYg==");
+	}
+}
@@ -0,0 +1,215 @@
+use std::io;
+use std::sync::RwLock;
+use std::ops::Bound;
+use std::collections::{BTreeMap, HashMap};
+use std::vec;
+
+// Records fetched from the store at a time by `entries`.
+const PAGE: usize = 1000;
+
+pub enum BatchOp {
+	Put(String, String),
+	Delete(String),
+}
+
+// Storage behind the key -> record index. Implementations do their own
+// locking, so one store can be shared by everything that touches the index.
+pub trait IndexStore: Send + Sync {
+	fn get(&self, key: &str) -> Option<String>;
+
+	// Applies every operation in order, or none of them if the batch could
+	// not be written.
+	fn write_batch(&self, batch: Vec<BatchOp>) -> io::Result<()>;
+
+	// Entries whose key starts with `prefix` and is >= `start`, in ascending
+	// key order, at most `limit` of them (0 means no limit).
+	fn scan(&self, prefix: &str, start: &str, limit: usize) -> Vec<(String, String)>;
+
+	fn clear(&self) -> io::Result<()>;
+
+	fn put(&self, key: &str, value: String) -> io::Result<()> {
+		self.write_batch(vec![BatchOp::Put(key.to_string(), value)])
+	}
+
+	fn delete(&self, key: &str) -> io::Result<()> {
+		self.write_batch(vec![BatchOp::Delete(key.to_string())])
+	}
+}
+
+// Every entry of `store` whose key starts with `prefix` and is >= `start`, in
+// ascending key order. Scans the store a page at a time, so writes made while
+// iterating may or may not show up.
+pub fn entries<'a>(store: &'a dyn IndexStore, prefix: &str, start: &str) -> Entries<'a> {
+	Entries {
+		store,
+		prefix: prefix.to_string(),
+		cursor: start.to_string(),
+		page: Vec::new().into_iter(),
+		done: false,
+	}
+}
+
+pub struct Entries<'a> {
+	store: &'a dyn IndexStore,
+	prefix: String,
+	cursor: String,
+	page: vec::IntoIter<(String, String)>,
+	done: bool,
+}
+
+impl<'a> Iterator for Entries<'a> {
+	type Item = (String, String);
+
+	fn next(&mut self) -> Option<Self::Item> {
+		if let Some(entry) = self.page.next() {
+			return Some(entry);
+		}
+
+		if self.done { return None; }
+
+		let page = self.store.scan(&self.prefix, &self.cursor, PAGE);
+		self.done = page.len() < PAGE;
+
+		if let Some((k, _)) = page.last() {
+			// Smallest key that sorts after the last one seen.
+			self.cursor = format!("{}\0", k);
+		}
+
+		self.page = page.into_iter();
+		self.page.next()
+	}
+}
+
+// Unordered in-memory index, nothing survives the process. Scans have to
+// sort the whole map, so this is meant for tests and throwaway clusters.
+#[derive(Default)]
+pub struct MemoryStore {
+	map: RwLock<HashMap<String, String>>,
+}
+
+impl IndexStore for MemoryStore {
+	fn get(&self, key: &str) -> Option<String> {
+		self.map.read().unwrap().get(key).cloned()
+	}
+
+	fn write_batch(&self, batch: Vec<BatchOp>) -> io::Result<()> {
+		let mut map = self.map.write().unwrap();
+
+		for op in batch {
+			match op {
+				BatchOp::Put(k, v) => { map.insert(k, v); },
+				BatchOp::Delete(k) => { map.remove(&k); },
+			}
+		}
+
+		Ok(())
+	}
+
+	fn scan(&self, prefix: &str, start: &str, limit: usize) -> Vec<(String, String)> {
+		let map = self.map.read().unwrap();
+
+		let mut entries = map.iter()
+			.filter(|(k, _)| k.starts_with(prefix) && k.as_str() >= start)
+			.map(|(k, v)| (k.clone(), v.clone()))
+			.collect::<Vec<(String, String)>>();
+
+		entries.sort();
+
+		if limit > 0 { entries.truncate(limit); }
+
+		entries
+	}
+
+	fn clear(&self) -> io::Result<()> {
+		self.map.write().unwrap().clear();
+		Ok(())
+	}
+}
+
+// Sorted in-memory index, cheap ordered scans for listing.
+#[derive(Default)]
+pub struct TreeStore {
+	map: RwLock<BTreeMap<String, String>>,
+}
+
+impl IndexStore for TreeStore {
+	fn get(&self, key: &str) -> Option<String> {
+		self.map.read().unwrap().get(key).cloned()
+	}
+
+	fn write_batch(&self, batch: Vec<BatchOp>) -> io::Result<()> {
+		let mut map = self.map.write().unwrap();
+		apply(&mut map, batch);
+		Ok(())
+	}
+
+	fn scan(&self, prefix: &str, start: &str, limit: usize) -> Vec<(String, String)> {
+		scan(&self.map.read().unwrap(), prefix, start, limit)
+	}
+
+	fn clear(&self) -> io::Result<()> {
+		self.map.write().unwrap().clear();
+		Ok(())
+	}
+}
+
+pub fn apply(map: &mut BTreeMap<String, String>, batch: Vec<BatchOp>) {
+	for op in batch {
+		match op {
+			BatchOp::Put(k, v) => { map.insert(k, v); },
+			BatchOp::Delete(k) => { map.remove(&k); },
+		}
+	}
+}
+
+pub fn scan(map: &BTreeMap<String, String>, prefix: &str, start: &str, limit: usize) -> Vec<(String, String)> {
+	let from = if start > prefix { start } else { prefix };
+
+	// Keys sharing a prefix are contiguous, so stop at the first one that doesn't.
+	let iter = map.range::<str, _>((Bound::Included(from), Bound::Unbounded))
+		.take_while(|(k, _)| k.starts_with(prefix))
+		.map(|(k, v)| (k.clone(), v.clone()));
+
+	if limit > 0 {
+		iter.take(limit).collect()
+	} else {
+		iter.collect()
+	}
+}
+
+#[cfg(test)]
+mod tests {
+	use super::*;
+
+	fn store(keys: usize) -> TreeStore {
+		let store = TreeStore::default();
+		let batch = (0..keys).map(|i| BatchOp::Put(format!("k{:05}", i), i.to_string())).collect();
+		store.write_batch(batch).unwrap();
+		store
+	}
+
+	#[test]
+	fn entries_go_across_pages() {
+		let store = store(PAGE * 2 + 1);
+		let keys = entries(&store, "", "").map(|(k, _)| k).collect::<Vec<String>>();
+
+		assert_eq!(keys.len(), PAGE * 2 + 1);
+		assert!(keys.windows(2).all(|w| w[0] < w[1]));
+	}
+
+	#[test]
+	fn entries_stop_on_a_full_last_page() {
+		assert_eq!(entries(&store(PAGE), "", "").count(), PAGE);
+		assert_eq!(entries(&store(0), "", "").count(), 0);
+	}
+
+	#[test]
+	fn entries_keep_to_prefix_and_start() {
+		let store = store(PAGE * 2);
+
+		// k01000 to k01999
+		assert_eq!(entries(&store, "k01", "").count(), PAGE);
+		assert_eq!(entries(&store, "k01", "k01500").next().unwrap().0, "k01500");
+		assert_eq!(entries(&store, "", "k01999\0").count(), 0);
+	}
+}
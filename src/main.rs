@@ -1,9 +1,27 @@
+mod db;
+mod index;
+mod digest;
+mod erasure;
 mod record;
 mod hash;
 mod remote;
+mod scrub;
+mod progress;
 mod mkv;
 
-use mkv::Minikeyvalue;
+#[cfg(test)]
+mod stub;
+
+use db::LogStore;
+use index::{IndexStore, MemoryStore, TreeStore};
+use mkv::{Config, Listen, Minikeyvalue};
+use digest::Algorithm;
+use hash::Volume;
+use erasure::Codec;
+
+use std::net::SocketAddr;
+use std::path::Path;
+use std::time::Duration;
 
 use clap::{App, Arg};
 
@@ -12,25 +30,52 @@ fn main() {
 					.version("0.1.0")
 					.author("Tanishq Jain <tanishqjain1002@gmail.com>")
 					.about("A Rust port of minikeyvalue (https://github.com/geohot/minikeyvalue)")
-					.usage("Usage: ./mkv <server, rebuild, rebalance> [FLAGS] [OPTIONS]")
+					.usage("Usage: ./mkv <server, rebuild, rebalance, scrub> [FLAGS] [OPTIONS]")
 					.arg(Arg::with_name("command")
-							.help("Command to run from server, rebalance, rebuild")
+							.help("Command to run from server, rebalance, rebuild, scrub")
 							.required(true)
 							.index(1))
+					.arg(Arg::with_name("database")
+							.short("d")
+							.long("database")
+							.value_name("PATH")
+							.help("Path to the directory holding the index database")
+							.default_value("")
+							.takes_value(true))
+					.arg(Arg::with_name("index")
+							.short("i")
+							.long("index")
+							.value_name("TYPE")
+							.help("Index store to use from log, tree, memory")
+							.default_value("log")
+							.takes_value(true))
 					.arg(Arg::with_name("port")
 							.short("p")
 							.long("port")
 							.value_name("PORT")
-							.help("Port for the server to listen on")
+							.help("Port for the server to listen on when no --listen is given")
 							.default_value("3000")
 							.takes_value(true))
+					.arg(Arg::with_name("listen")
+							.short("l")
+							.long("listen")
+							.value_name("ADDRESS")
+							.help("Addresses for the server to listen on, comma separated, each as HOST:PORT, [IPV6]:PORT or unix:PATH, 127.0.0.1:<port> by default")
+							.default_value("")
+							.takes_value(true))
 					.arg(Arg::with_name("fallback")
 							.short("f")
 							.long("fallback")
-							.value_name("PATH")
-							.help("Fallback server for missing keys")
+							.value_name("HOST:PORT")
+							.help("Server to redirect GETs of missing keys to, such as the cluster being migrated from")
 							.default_value("")
 							.takes_value(true))
+					.arg(Arg::with_name("migrate")
+							.long("migrate")
+							.help("Copy keys served from the fallback into this cluster in the background"))
+					.arg(Arg::with_name("proxy")
+							.long("proxy")
+							.help("Stream blobs to clients instead of redirecting them to the volumes"))
 					.arg(Arg::with_name("replicas")
 							.short("r")
 							.long("replicas")
@@ -38,6 +83,13 @@ fn main() {
 							.help("Amount of replicas to make of the data")
 							.default_value("3")
 							.takes_value(true))
+					.arg(Arg::with_name("write_quorum")
+							.short("w")
+							.long("write-quorum")
+							.value_name("INT")
+							.help("Replicas that have to be written for a PUT to succeed, all of them by default")
+							.default_value("")
+							.takes_value(true))
 					.arg(Arg::with_name("subvolumes")
 							.short("s")
 							.long("subvolumes")
@@ -49,9 +101,55 @@ fn main() {
 							.short("v")
 							.long("volumes")
 							.value_name("PATH")
-							.help("Volumes to use for storage, comma separated")
+							.help("Volumes to use for storage, comma separated, each as HOST:PORT[=WEIGHT][@DOMAIN]")
 							.default_value("")
 							.takes_value(true))
+					.arg(Arg::with_name("threads")
+							.short("t")
+							.long("threads")
+							.value_name("INT")
+							.help("Amount of worker threads serving requests or rebuilding and rebalancing")
+							.default_value("16")
+							.takes_value(true))
+					.arg(Arg::with_name("hash")
+							.long("hash")
+							.value_name("ALGORITHM")
+							.help("Hash for the integrity of stored data from md5, sha256, blake3, xxh3")
+							.default_value("md5")
+							.takes_value(true))
+					.arg(Arg::with_name("erasure")
+							.long("erasure")
+							.value_name("DATA,PARITY")
+							.help("Store new keys as Reed-Solomon shards instead of replicas")
+							.default_value("")
+							.takes_value(true))
+					.arg(Arg::with_name("scrub_interval")
+							.long("scrub-interval")
+							.value_name("SECONDS")
+							.help("Scrub the volumes in the background of the server this often, never if 0")
+							.default_value("0")
+							.takes_value(true))
+					.arg(Arg::with_name("scrub_rate")
+							.long("scrub-rate")
+							.value_name("BYTES")
+							.help("Bytes per second scrubbing may read from the volumes, no limit if 0")
+							.default_value("0")
+							.takes_value(true))
+					.arg(Arg::with_name("repair")
+							.long("repair")
+							.help("Rewrite missing or corrupt replicas found while scrubbing"))
+					.arg(Arg::with_name("checkpoint")
+							.long("checkpoint")
+							.value_name("PATH")
+							.help("File to track the progress of rebuild and rebalance in, <database>/<command>.checkpoint by default")
+							.default_value("")
+							.takes_value(true))
+					.arg(Arg::with_name("resume")
+							.long("resume")
+							.help("Carry on with an interrupted rebuild or rebalance from its checkpoint"))
+					.arg(Arg::with_name("dry_run")
+							.long("dry-run")
+							.help("Print what a rebalance would copy and delete as JSON instead of doing it"))
 					.arg(Arg::with_name("unlink")
 							.short("u")
 							.long("unlink")
@@ -61,26 +159,100 @@ fn main() {
 
 	let command = matches.value_of("command").unwrap();
 	
-	let volumes: Vec<String> = matches.value_of("volumes").unwrap().split(',').map(|x| x.to_string()).collect();
+	let volumes: Vec<Volume> = matches.value_of("volumes").unwrap().split(',').map(|x| x.parse::<Volume>().expect("could not parse volumes")).collect();
 	let fallback = matches.value_of("fallback").unwrap().to_string();
 	let replicas = matches.value_of("replicas").unwrap().parse::<i32>().expect("could not parse replicas");
 	let subvolumes = matches.value_of("subvolumes").unwrap().parse::<i32>().expect("could not parse subvolumes");
 	let protect = matches.value_of("unlink").unwrap_or("false").parse::<bool>().unwrap();
 	let port = matches.value_of("port").unwrap().parse::<u16>().expect("could not parse port");
+	let listen: Vec<Listen> = match matches.value_of("listen").unwrap() {
+		"" => vec![Listen::Tcp(SocketAddr::from(([127, 0, 0, 1], port)))],
+		l => l.split(',').map(|x| x.parse::<Listen>().expect("could not parse listen address")).collect(),
+	};
+	let threads = matches.value_of("threads").unwrap().parse::<usize>().expect("could not parse threads");
+	let algorithm = matches.value_of("hash").unwrap().parse::<Algorithm>().expect("could not parse hash");
+	let erasure = match matches.value_of("erasure").unwrap() {
+		"" => None,
+		e => Some(e.parse::<Codec>().expect("could not parse erasure")),
+	};
+	let copies = erasure.map(|c| c.shards()).unwrap_or(replicas as usize);
+	let write_quorum = match matches.value_of("write_quorum").unwrap() {
+		"" => copies,
+		w => w.parse::<usize>().expect("could not parse write quorum"),
+	};
+	let scrub_interval = match matches.value_of("scrub_interval").unwrap().parse::<u64>().expect("could not parse scrub interval") {
+		0 => None,
+		secs => Some(Duration::from_secs(secs)),
+	};
+	let scrub_rate = matches.value_of("scrub_rate").unwrap().parse::<u64>().expect("could not parse scrub rate");
+	let scrub_repair = matches.is_present("repair");
+	let database = matches.value_of("database").unwrap();
+	let index = matches.value_of("index").unwrap();
+	let checkpoint = match matches.value_of("checkpoint").unwrap() {
+		"" if database.is_empty() => None,
+		"" => Some(Path::new(database).join(format!("{}.checkpoint", command))),
+		path => Some(Path::new(path).to_path_buf()),
+	};
+	let resume = matches.is_present("resume");
+	let dry_run = matches.is_present("dry_run");
+	let migrate = matches.is_present("migrate");
+	let proxy = matches.is_present("proxy");
 
-	if command != "server" && command != "rebalance" && command != "rebuild" {
+	if command != "server" && command != "rebalance" && command != "rebuild" && command != "scrub" {
 		panic!("{}", matches.usage());
 	}
 
-	if matches.value_of("database").unwrap() == "" {
+	if resume && checkpoint.is_none() {
+		panic!("Need a checkpoint or a database to resume");
+	}
+
+	if migrate && fallback.is_empty() {
+		panic!("Need a fallback to migrate from");
+	}
+
+	if index == "log" && database.is_empty() {
 		panic!("Need a path to the database");
 	}
 
 	if volumes.len() < matches.value_of("replicas").unwrap().parse::<usize>().expect("Cannot parse replicas to INT") {
 		panic!("Need at least as many volumes as replicas");
+	}
+
+	if erasure.map(|c| volumes.len() < c.shards()).unwrap_or(false) {
+		panic!("Need at least as many volumes as erasure coded shards");
+	}
+
+	if write_quorum == 0 || write_quorum > copies || erasure.map(|c| write_quorum < c.data).unwrap_or(false) {
+		panic!("Write quorum must be at least 1, or the data shards with erasure coding, and at most the number of copies");
 	}	
 
-	let mut mkv = Minikeyvalue::new(volumes, fallback, replicas, subvolumes, protect, port);
+	let db: Box<dyn IndexStore> = match index {
+		"log" => Box::new(LogStore::open(database).expect("could not open the database")),
+		"tree" => Box::new(TreeStore::default()),
+		"memory" => Box::new(MemoryStore::default()),
+		_ => panic!("Unknown index store {}", index),
+	};
+
+	let mkv = Minikeyvalue::new(db, Config {
+		volumes,
+		fallback,
+		replicas,
+		subvolumes,
+		listen,
+		protect,
+		threads,
+		algorithm,
+		erasure,
+		write_quorum,
+		scrub_interval,
+		scrub_rate,
+		scrub_repair,
+		checkpoint,
+		resume,
+		dry_run,
+		migrate,
+		proxy,
+	});
 
 	if command == "server" {
 		mkv.server();
@@ -88,5 +260,7 @@ fn main() {
 		mkv.rebalance();
 	} else if command == "rebuild" {
 		mkv.rebuild();
+	} else if command == "scrub" {
+		mkv.scrub();
 	}
 }
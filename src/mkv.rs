@@ -1,19 +1,33 @@
-use std::u8;
 use std::str;
+use std::io::{self, Read};
 use std::mem::drop;
+use std::thread;
+use std::panic::{self, AssertUnwindSafe};
+use std::time::Duration;
+use std::fs;
 use std::net::SocketAddr;
+use std::os::unix::fs::FileTypeExt;
+use std::path::PathBuf;
+use std::sync::atomic::Ordering;
 
+use std::str::FromStr;
 use std::{fmt, num::ParseIntError};
 use std::sync::{Mutex, Arc};
-use std::collections::HashMap;
+use std::collections::{BTreeMap, HashMap, HashSet};
 
+use crate::index::{self, IndexStore};
 use crate::hash::*;
+use crate::digest::{Algorithm, Digest, DigestReader};
+use crate::erasure::{self, Codec, Layout, ShardReader};
 use crate::remote::*;
 use crate::record::{Record, Deleted};
+use crate::scrub::{self, Budget, Report, Status};
+use crate::progress::{Progress, Checkpoint};
 
 use ascii::AsciiString;
+use crossbeam::channel::{self, Sender, Receiver};
 use serde::{Deserialize, Serialize};
-use tiny_http::{Server, Method, Response, Header};
+use tiny_http::{Server, Request, Method, Response, Header};
 
 
 #[derive(Clone)]
@@ -29,10 +43,13 @@ pub struct RebuildRequest {
 	url: String,
 }
 
+// An entry of a volume's directory listing, as nginx's autoindex sends it.
 #[derive(Clone, Deserialize, Serialize)]
 struct File {
 	name: String,
+	#[serde(rename = "type")]
 	file_type: String,
+	#[serde(rename = "mtime", default)]
 	time: String,
 }
 
@@ -41,18 +58,51 @@ struct FileWrapper(Vec<File>);
 
 impl FileWrapper {
 	fn new() -> Self {
-		Self {
-			0: vec![],
-		}
+		Self(vec![])
 	}
 }
 
+// Listings without a limit are refused past this many keys.
+const LIST_MAX: usize = 1000000;
+
+// Keys waiting for a repair or a migration before new ones are turned away.
+const REPAIR_QUEUE: usize = 10000;
+
+// Threads of the server working through each of those queues.
+const REPAIR_WORKERS: usize = 4;
+
 #[derive(Clone, Deserialize, Serialize, Default)]
 struct ListResponse {
 	next: String,
 	keys: Vec<String>,
 }
 
+// What `rebalance --dry-run` would do.
+#[derive(Serialize, Default)]
+struct Plan {
+	keys: u64,
+	moves: Vec<Move>,
+	bytes: u64,
+	volumes: BTreeMap<String, VolumeCount>,
+}
+
+// Copies of one key to write and then delete. `bytes` is what has to be
+// copied, unknown if no volume answered with the size of the blob.
+#[derive(Serialize)]
+struct Move {
+	key: String,
+	copies: Vec<String>,
+	deletes: Vec<String>,
+	bytes: Option<u64>,
+}
+
+// Copies a volume holds before and after the rebalance.
+#[derive(Serialize, Default)]
+struct VolumeCount {
+	before: u64,
+	after: u64,
+}
+
 #[derive(Debug, Clone, PartialEq, Eq)]
 pub enum DecodeHexError {
 	OddLength,
@@ -76,460 +126,1174 @@ impl fmt::Display for DecodeHexError {
 
 impl std::error::Error for DecodeHexError {}
 
+// Settings taken from the command line.
+pub struct Config {
+	pub volumes: Vec<Volume>,
+	pub fallback: String,
+	pub replicas: i32,
+	pub subvolumes: i32,
+	pub listen: Vec<Listen>,
+	pub protect: bool,
+	pub threads: usize,
+	pub algorithm: Algorithm,
+	pub erasure: Option<Codec>,
+	pub write_quorum: usize,
+	pub scrub_interval: Option<Duration>,
+	pub scrub_rate: u64,
+	pub scrub_repair: bool,
+	pub checkpoint: Option<PathBuf>,
+	pub resume: bool,
+	pub dry_run: bool,
+	pub migrate: bool,
+	pub proxy: bool,
+}
+
+// Where the server takes requests, from `--listen`: `HOST:PORT` with an IPv4
+// or IPv6 (in brackets) address, or `unix:PATH` for a Unix domain socket.
+#[derive(Clone, Debug)]
+pub enum Listen {
+	Tcp(SocketAddr),
+	Unix(PathBuf),
+}
+
+impl FromStr for Listen {
+	type Err = String;
+
+	fn from_str(s: &str) -> Result<Self, Self::Err> {
+		match s.strip_prefix("unix:") {
+			Some("") => Err("unix socket needs a path".to_string()),
+			Some(path) => Ok(Listen::Unix(PathBuf::from(path))),
+			None => s.parse::<SocketAddr>().map(Listen::Tcp).map_err(|e| format!("bad listen address {}: {}", s, e)),
+		}
+	}
+}
+
+impl fmt::Display for Listen {
+	fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+		match self {
+			Listen::Tcp(addr) => write!(f, "{}", addr),
+			Listen::Unix(path) => write!(f, "unix:{}", path.display()),
+		}
+	}
+}
+
+// Write lock on a single key, released when dropped.
+pub struct KeyLock {
+	lock: Arc<Mutex<HashMap<String, u8>>>,
+	key: String,
+}
+
+impl Drop for KeyLock {
+	fn drop(&mut self) {
+		let mut map = self.lock.lock().unwrap();
+		map.remove(&self.key);
+	}
+}
+
+// Keys waiting for the server to do something about them in the background,
+// each queued at most once.
+#[derive(Clone)]
+struct KeyQueue {
+	name: &'static str,
+	tx: Sender<String>,
+	rx: Receiver<String>,
+	queued: Arc<Mutex<HashSet<String>>>,
+}
+
+impl KeyQueue {
+	fn new(name: &'static str) -> Self {
+		let (tx, rx) = channel::bounded(REPAIR_QUEUE);
+		Self { name, tx, rx, queued: Arc::new(Mutex::new(HashSet::new())) }
+	}
+
+	fn push(&self, key: &str) {
+		let mut queued = self.queued.lock().unwrap();
+
+		if !queued.insert(key.to_string()) { return; }
+
+		if self.tx.try_send(key.to_string()).is_err() {
+			eprintln!("{} queue is full, dropping {}", self.name, key);
+			queued.remove(key);
+		}
+	}
+
+	// Blocks for the next key. It is taken off the queue first, so a failure
+	// seen while working on it queues it again.
+	fn pop(&self) -> String {
+		let key = self.rx.recv().expect("queue closed");
+		self.queued.lock().unwrap().remove(&key);
+		key
+	}
+}
+
 #[derive(Clone)]
 pub struct Minikeyvalue {
-	db: HashMap<String, String>,
+	db: Arc<dyn IndexStore>,
 	lock: Arc<Mutex<HashMap<String, u8>>>, 
-	volumes: Vec<String>,
+	volumes: Vec<Volume>,
 	fallback: String,
 	replicas: i32,
 	subvolumes: i32,
-	port: u16,
+	listen: Vec<Listen>,
 	protect: bool,
+	threads: usize,
+	algorithm: Algorithm,
+	erasure: Option<Codec>,
+	write_quorum: usize,
+	scrub_interval: Option<Duration>,
+	scrub_rate: u64,
+	scrub_repair: bool,
+	checkpoint: Option<PathBuf>,
+	resume: bool,
+	dry_run: bool,
+	migrate: bool,
+	proxy: bool,
+	repairs: KeyQueue,
+	migrations: KeyQueue,
 }
 
 impl Minikeyvalue {
-	pub fn new(volumes: Vec<String>, fallback: String, replicas: i32, subvolumes: i32, protect: bool, port: u16) -> Self {
+	pub fn new(db: Box<dyn IndexStore>, config: Config) -> Self {
 		Self {
-			db: HashMap::new(),
+			db: Arc::from(db),
 			lock: Arc::new(Mutex::new(HashMap::new())),
-			volumes,
-			fallback,
-			replicas,
-			subvolumes,
-			port,
-			protect,
+			volumes: config.volumes,
+			fallback: config.fallback,
+			replicas: config.replicas,
+			subvolumes: config.subvolumes,
+			listen: config.listen,
+			protect: config.protect,
+			threads: config.threads,
+			algorithm: config.algorithm,
+			erasure: config.erasure,
+			write_quorum: config.write_quorum,
+			scrub_interval: config.scrub_interval,
+			scrub_rate: config.scrub_rate,
+			scrub_repair: config.scrub_repair,
+			checkpoint: config.checkpoint,
+			resume: config.resume,
+			dry_run: config.dry_run,
+			migrate: config.migrate,
+			proxy: config.proxy,
+			repairs: KeyQueue::new("repair"),
+			migrations: KeyQueue::new("migration"),
 		}
 	}
 
-	pub fn unlock_key(&self, key: &str) {
-		let mut map = self.lock.lock().unwrap();
-		map.remove(key);
-		drop(map);
-	}
-
-	pub fn lock_key(&self, key: &str) -> bool {
+	// Takes the write lock on `key`, `None` if someone else is holding it. The
+	// key stays locked until the returned guard is dropped.
+	pub fn lock_key(&self, key: &str) -> Option<KeyLock> {
 		let mut map = self.lock.lock().unwrap();
 
-		if map.contains_key(key) { return false; }
+		if map.contains_key(key) { return None; }
 		
 		map.insert(key.to_string(), 1);
 		drop(map);
 
-		true
+		Some(KeyLock { lock: self.lock.clone(), key: key.to_string() })
+	}
+
+	// Like `lock_key`, but waits for the current holder to let go.
+	pub fn lock_key_wait(&self, key: &str) -> KeyLock {
+		loop {
+			if let Some(guard) = self.lock_key(key) { return guard; }
+			thread::sleep(Duration::from_millis(1));
+		}
 	}
 
 	pub fn get_record(&self, key: &str) -> Record {
 		match self.db.get(key) {
-			Some(val) => val.to_string().into(),
+			Some(val) => val.into(),
 			None => Record::new(),
 		}
 	}
 
-	// If `None` is returned, it is the first insertion else if `String` is returned,
-	// then it updates the previous record and returns the old value. Will never fail
-	// probably.
-	pub fn put_record(&mut self, key: &str, rec: Record) -> Option<String> {
-		self.db.insert(key.to_string(), rec.into())
+	// Fails only if the index could not store the record.
+	pub fn put_record(&self, key: &str, rec: Record) -> io::Result<()> {
+		self.db.put(key, rec.into())
+	}
+
+	// Queues `key` to have its missing replicas written again by the server,
+	// unless it is waiting for that already.
+	fn enqueue_repair(&self, key: &str) {
+		self.repairs.push(key);
+	}
+
+	// Puts back the record a failed PUT replaced, or removes the one it added.
+	fn restore_record(&self, key: &str, previous: Option<String>) {
+		let result = match previous {
+			Some(value) => self.db.put(key, value),
+			None => self.db.delete(key),
+		};
+
+		if let Err(e) = result {
+			eprintln!("cannot restore the record of {}: {}", key, e);
+		}
+	}
+
+	// Volumes `key` belongs on, as many as the record has replicas or shards.
+	fn placement(&self, key: &str, rec: &Record) -> Vec<String> {
+		let count = rec.erasure.map(|l| l.codec.shards() as i32).unwrap_or(self.replicas);
+		key_to_volume(key, &self.volumes, count, self.subvolumes)
+	}
+
+	// Keys under `prefix` starting at `start`, in key order. With a `limit`, `next`
+	// is the first key that did not fit and can be passed back as `start` to get
+	// the following page. `None` if an unlimited listing grows too large.
+	fn list(&self, prefix: &str, start: &str, limit: usize, unlinked: bool) -> Option<ListResponse> {
+		let mut lr = ListResponse::default();
+
+		for (k, v) in index::entries(&*self.db, prefix, start) {
+			let rec = Record::from(v);
+
+			if (rec.deleted != Deleted::No && !unlinked) || (rec.deleted != Deleted::Soft && unlinked) || rec.is_tombstone() {
+				continue;
+			}
+
+			if limit > 0 && lr.keys.len() == limit {
+				lr.next = k;
+				return Some(lr);
+			}
+
+			if lr.keys.len() >= LIST_MAX { return None; }
+
+			lr.keys.push(k);
+		}
+
+		Some(lr)
 	}
 
-	pub fn rebuild(&mut self) {
-		self.db.clear();
+	// Clears the index and fills it again from what the volumes hold. The top
+	// level directories of the volumes are handed out to `threads` workers.
+	// With `--resume`, an interrupted rebuild carries on after the last
+	// directory it finished instead.
+	pub fn rebuild(&self) {
+		let checkpoint = Checkpoint::new(self.checkpoint.clone(), "rebuild");
+		let resume = self.resume_from(&checkpoint);
+
+		if resume.is_none() {
+			self.db.clear().expect("rebuild: cannot clear the database");
+		}
 
-		let mut reqs = Vec::<RebuildRequest>::with_capacity(20000);
+		// Listed up front, so there is a total to go by.
+		let mut reqs = Vec::<RebuildRequest>::new();
 
-		for vol in self.volumes.iter() {
+		for vol in self.volumes.iter().map(|v| &v.addr) {
 			let mut has_subvolumes = false;
 
 			for f in get_files(&format!("http://{}/", vol)).0 {
 				if f.name.len() == 4 && f.name.starts_with("sv") && f.file_type == "directory" {
-					if let Some(v) = parse_volume(format!("{}/{}", vol, f.name)) {
-						reqs.push(v);
-					}
-
+					reqs.extend(parse_volume(format!("{}/{}", vol, f.name)));
 					has_subvolumes = true;
 				}
 			}
 
 			if !has_subvolumes {
-				if let Some(v) = parse_volume(vol.to_string()) {
-					reqs.push(v);
-				}	
+				reqs.extend(parse_volume(vol.to_string()));
+			}
+		}
+
+		if let Some(url) = resume {
+			match reqs.iter().position(|r| r.url == url) {
+				Some(i) => { reqs.drain(..=i); },
+				None => eprintln!("rebuild: {} from the checkpoint is gone, going through everything", url),
 			}
 		}
 
-		for _i in 0..128 {
-			crossbeam::scope(|scope| {
-				scope.spawn({
-					let reqs_c = reqs.clone();
-					let mut that = self.clone();
+		let progress = Progress::new("rebuild", "directories", "replicas found");
+		progress.total.store(reqs.len() as u64, Ordering::SeqCst);
+
+		let (tx, rx) = channel::bounded::<(u64, RebuildRequest)>(self.threads * 4);
+
+		crossbeam::scope(|scope| {
+			scope.spawn(|_| progress.report(&checkpoint));
 
-					move |_| {
-						for req in reqs_c.iter() {
-							for f in get_files(&req.url).0 {
-								rebuild(&mut that, &req.vol, &f.name);
+			let workers = (0..self.threads).map(|_| {
+				let rx = rx.clone();
+
+				scope.spawn(|_| {
+					for (seq, req) in rx {
+						for dir in get_files(&req.url).0.iter().filter(|d| valid(d)) {
+							for f in get_files(&format!("{}{}/", req.url, dir.name)).0 {
+								if f.file_type != "file" || f.name.ends_with(".layout") { continue; }
+
+								let counter = if rebuild(self, &req.vol, &f.name) { &progress.changed } else { &progress.failed };
+								counter.fetch_add(1, Ordering::SeqCst);
 							}
 						}
+
+						progress.scanned.fetch_add(1, Ordering::SeqCst);
+						checkpoint.done(seq, &req.url);
 					}
-				});
-			}).expect("rebalance: crossbeam failed");
-		}
-	}
+				})
+			}).collect::<Vec<_>>();
 
-	pub fn rebalance(&mut self) {
-		let mut reqs = Vec::<RebalanceRequest>::with_capacity(20000);
+			for req in reqs {
+				tx.send((checkpoint.start(), req)).expect("rebuild: workers are gone");
+			}
 
-		for (key, value) in self.db.iter() {
-			let rec = Record::from(value.to_string());
-			let kvolumes = key_to_volume(key, &self.volumes, self.replicas, self.subvolumes);
+			drop(tx);
 
-			reqs.push(RebalanceRequest {
-				key: key.to_string(),
-				kvolumes,
-				volumes: rec.rvolumes,
-			});
-		}
+			let joined = workers.into_iter().all(|w| w.join().is_ok());
+			progress.finish();
 
-		for _i in 0..16 {
-			crossbeam::scope(|scope| {
-				let reqs_c = reqs.clone();
-				let mut that = self.clone();
+			assert!(joined, "rebuild: worker panicked");
+		}).expect("rebuild: crossbeam failed");
 
-				scope.spawn(move |_| {
-					for req in reqs_c.iter() {
-						rebalance(&mut that, req);
-					}
-				});	
-			}).expect("rebalance: crossbeam failed");
-		}
+		checkpoint.clear().expect("rebuild: cannot remove the checkpoint");
 	}
 
-	pub fn server(&mut self) {
-		println!("[OK] Listening on 127.0.0.1:{}", self.port);
-		let addr = SocketAddr::from(([127, 0, 0, 1], self.port));
-
-		let server = Server::http(addr).unwrap();
-		let method_unlink = &Method::NonStandard(AsciiString::from_ascii("UNLINK").unwrap());
-		let method_rebalance = &Method::NonStandard(AsciiString::from_ascii("REBALANCE").unwrap());
+	// Moves every key in the index onto the volumes it belongs on, `threads`
+	// keys at a time. With `--resume`, an interrupted rebalance carries on
+	// after the last key it finished instead.
+	pub fn rebalance(&self) {
+		if self.dry_run {
+			self.plan();
+			return;
+		}
 
-		for mut req in server.incoming_requests().by_ref() {
-			let split = req.url().split('?').map(|x| x.to_string()).collect::<Vec<String>>();
-			let key = split[0].clone();
-			let q = split[1].clone();
+		let checkpoint = Checkpoint::new(self.checkpoint.clone(), "rebalance");
+		let start = self.resume_from(&checkpoint).map(|k| format!("{}\0", k)).unwrap_or_default();
 
-			let method = req.method();
+		let progress = Progress::new("rebalance", "keys", "moved");
+		progress.total.store(self.count(&start), Ordering::SeqCst);
 
-			let mut query = HashMap::new();
+		let (tx, rx) = channel::bounded::<(u64, RebalanceRequest)>(self.threads * 4);
 
-			let qs = q.split('&').collect::<Vec<&str>>();
+		crossbeam::scope(|scope| {
+			scope.spawn(|_| progress.report(&checkpoint));
 
-			qs.iter()
-				.for_each(|x| {
-				for (_i, v) in x.split('=')
-								.collect::<Vec<&str>>()
-								.chunks(2)
-								.enumerate() {
-					query.insert(v[0], v[1]);
-				}
-			});
+			let workers = (0..self.threads).map(|_| {
+				let rx = rx.clone();
 
-			if query.len() > 0 {
-				if method == &Method::Get {
-					req.respond(Response::empty(403)).expect("error while responding");
-					return;
-				}
+				scope.spawn(|_| {
+					for (seq, req) in rx {
+						let _guard = self.lock_key_wait(&req.key);
+						let moving = needs_rebalance(&req.volumes, &req.kvolumes, &self.volumes);
 
-				let operation = &qs[0];
-				match *operation {
-					"link" | "unlinked" => {
-						let q_limit = query.get("limit");
-
-						let mut limit = 0;
-
-						let qlimit = if q_limit.is_some() {
-							q_limit.unwrap()
-						} else {
-							&""
-						};
-
-						if qlimit != &"" {
-							match qlimit.parse::<i32>() {
-								Ok(nlimit) => limit = nlimit,
-								Err(_e) => {
-									req.respond(Response::empty(400)).expect("error while responding");
-									return;
-								},
-							}
+						if !rebalance(self, &req) {
+							progress.failed.fetch_add(1, Ordering::SeqCst);
+						} else if moving {
+							progress.changed.fetch_add(1, Ordering::SeqCst);
 						}
 
-						let mut counter = 0;
-						let mut keys = Vec::<String>::new();
-						let mut next = String::new();
+						progress.scanned.fetch_add(1, Ordering::SeqCst);
+						checkpoint.done(seq, &req.key);
+					}
+				})
+			}).collect::<Vec<_>>();
 
-						for (k, v) in self.db.iter() {
-							let rec = Record::from(v.clone());
+			for (key, value) in index::entries(&*self.db, "", &start) {
+				let rec = Record::from(value);
+				let kvolumes = self.placement(&key, &rec);
 
-							if (rec.deleted != Deleted::No && operation == &"list") || (rec.deleted != Deleted::Soft && operation == &"unlinked") {
-								continue;
-							}
+				let req = RebalanceRequest { key, kvolumes, volumes: rec.rvolumes };
+				tx.send((checkpoint.start(), req)).expect("rebalance: workers are gone");
+			}
 
-							if counter > 1000000 {
-								req.respond(Response::empty(403)).expect("error while responding");
-								return;
-							}
+			drop(tx);
 
-							if limit > 0 && keys.len() as i32 == limit {
-								next = k.to_string();
-							}
+			let joined = workers.into_iter().all(|w| w.join().is_ok());
+			progress.finish();
 
-							counter += 1;
-							keys.push(k.to_string());
-						}
+			assert!(joined, "rebalance: worker panicked");
+		}).expect("rebalance: crossbeam failed");
 
-						let lsw = match serde_json::to_string(&ListResponse {next, keys}) {
-							Ok(v) => v,
-							Err(_e) => {
-								req.respond(Response::empty(500)).expect("error while responding");
-								return;
-							}
-						};
+		checkpoint.clear().expect("rebalance: cannot remove the checkpoint");
+	}
+
+	// Prints the plan of a rebalance as JSON without changing anything. The
+	// only requests to volumes are for the size of the blobs that would move.
+	fn plan(&self) {
+		let plan = Mutex::new(Plan::default());
+		let (tx, rx) = channel::bounded::<(String, Record)>(self.threads * 4);
 
-						let mut headers = Vec::new();
-						headers.push(Header::from_bytes(&b"Content-Type"[..], &b"application/json"[..]).unwrap());
+		crossbeam::scope(|scope| {
+			for _i in 0..self.threads {
+				let rx = rx.clone();
+				let plan = &plan;
 
-						let bytes = lsw.as_bytes();
-						let resp = Response::new(200.into(), headers, bytes, Some(bytes.len()), None);
-						req.respond(resp).expect("error while responding");
-						continue;
+				scope.spawn(move |_| {
+					for (key, rec) in rx {
+						if let Some(m) = plan_move(self, key, rec) {
+							let mut plan = plan.lock().unwrap();
+							plan.bytes += m.bytes.unwrap_or(0);
+							plan.moves.push(m);
+						}
 					}
-					_ => {
-						req.respond(Response::empty(403)).expect("error while responding");
-						continue;
+				});
+			}
+
+			for (key, value) in index::entries(&*self.db, "", "") {
+				let rec = Record::from(value);
+
+				// Rebalance leaves unlinked and deleted keys where they are.
+				if rec.deleted != Deleted::No { continue; }
+
+				let kvolumes = self.placement(&key, &rec);
+
+				{
+					let mut plan = plan.lock().unwrap();
+					plan.keys += 1;
+
+					for v in rec.rvolumes.iter().filter(|v| !v.is_empty()) {
+						plan.volumes.entry(volume_of(v)).or_default().before += 1;
 					}
-				}
-			} // end query handler
 
-			
-			if method == &Method::Put || method == &Method::Delete || method == method_unlink || method == method_rebalance {
-				if !self.lock_key(&key) {
-					req.respond(Response::empty(409)).expect("error while responding");
-					continue;
+					for v in kvolumes.iter() {
+						plan.volumes.entry(volume_of(v)).or_default().after += 1;
+					}
 				}
 
-				self.unlock_key(&key);
+				tx.send((key, rec)).expect("rebalance: workers are gone");
 			}
 
-			if method == &Method::Get ||  method == &Method::Head {
-				let rec = self.get_record(&key);
-				
-				let mut remote = String::new();
-				let mut resp = Response::empty(404);
+			drop(tx);
+		}).expect("rebalance: crossbeam failed");
 
-				if rec.hash.len() != 0 {
-					let header = Header::from_bytes(&b"Content-Md5"[..], rec.hash.as_bytes()).unwrap();
-					resp.add_header(header);
-				}
+		let mut plan = plan.into_inner().unwrap();
+		plan.moves.sort_by(|a, b| a.key.cmp(&b.key));
 
-				if rec.deleted == Deleted::Soft || rec.deleted == Deleted::Hard {
-					if self.fallback == "" {
-						let header = Header::from_bytes(&b"Content-Length"[..], &b"0"[..]).unwrap();
-						resp.add_header(header);
-						resp = Response::with_status_code(resp, 404);
-						req.respond(resp).expect("error while responding");
-						continue;
-					}
-				} else {
-					let kvolumes = key_to_volume(&key, &self.volumes, self.replicas, self.subvolumes);
+		println!("{}", serde_json::to_string_pretty(&plan).expect("rebalance: cannot encode the plan"));
+	}
 
-					if needs_rebalance(&rec.rvolumes, &kvolumes) {
-						eprintln!("On wrong volumes, needs rebalance");
-					}
+	// Where to pick up from with `--resume`, `None` to start from scratch.
+	fn resume_from(&self, checkpoint: &Checkpoint) -> Option<String> {
+		if !self.resume { return None; }
+
+		let label = checkpoint.load().expect("cannot read the checkpoint");
+
+		match &label {
+			Some(label) => println!("[OK] Resuming after {}", label),
+			None => println!("[OK] No checkpoint, starting from scratch"),
+		}
+
+		label
+	}
+
+	// Keys in the index from `start` on.
+	fn count(&self, start: &str) -> u64 {
+		index::entries(&*self.db, "", start).count() as u64
+	}
+
+	// Goes through every live key in the index, reading each copy back and
+	// checking it against the digest in the record.
+	pub fn scrub(&self) {
+		let mut budget = Budget::new(self.scrub_rate);
+		let mut report = Report::default();
 
-					let mut good = false;
-					for rvol in rec.rvolumes.iter() {
-						remote = format!("http://{}{}", rvol, key_to_path(&key));
+		for (key, value) in index::entries(&*self.db, "", "") {
+			let rec = Record::from(value);
 
-						if remote_head(&remote) {
-							good = true;
-							break;
+			if rec.deleted == Deleted::No {
+				scrub(self, &key, &rec, &mut budget, &mut report);
+			}
+		}
+
+		println!("[OK] Scrubbed {}", report);
+	}
+
+	pub fn server(&self) {
+		let servers = self.listen.iter().map(|l| {
+			let server = bind(l);
+			println!("[OK] Listening on {}", l);
+			server
+		}).collect::<Vec<Server>>();
+
+		let (tx, rx) = channel::bounded::<Request>(self.threads * 4);
+
+		// Every worker pulls requests off the same queue, whatever listener they
+		// came in on, so a slow volume only holds up the requests that actually
+		// touch it.
+		crossbeam::scope(|scope| {
+			for server in servers.iter() {
+				let tx = tx.clone();
+
+				scope.spawn(move |_| {
+					loop {
+						match server.recv() {
+							Ok(req) => tx.send(req).expect("server: request queue closed"),
+							Err(e) => eprintln!("server: error while receiving request: {}", e),
 						}
 					}
+				});
+			}
 
-					if !good {
-						let header = Header::from_bytes(&b"Content-Length"[..], &b"0"[..]).unwrap();
-						resp.add_header(header);
-						resp = Response::with_status_code(resp, 404);
-						req.respond(resp).expect("error while responding");
-						continue;
+			for _i in 0..self.threads {
+				let rx = rx.clone();
+
+				scope.spawn(move |_| {
+					for req in rx {
+						catch_panic("request handler", || self.serve(req));
 					}
-				}
+				});
+			}
 
-				resp = Response::with_header(resp, Header::from_bytes(&b"Location"[..], remote).unwrap());
-				resp = Response::with_header(resp, Header::from_bytes(&b"Content-Length"[..], &b"0"[..]).unwrap());
-				resp = Response::with_status_code(resp, 302);
+			if let Some(interval) = self.scrub_interval {
+				scope.spawn(move |_| {
+					loop {
+						thread::sleep(interval);
+						catch_panic("scrub", || self.scrub());
+					}
+				});
+			}
 
-				req.respond(resp).expect("error while responding");
-				continue;
-			} else if method == &Method::Put {
-				let mut flag = false;
-				for head in &req.headers().to_vec() {
-					if head.field.as_str() == "Content-Length" && head.value == "0" {
-						flag = true;	
+			for _i in 0..REPAIR_WORKERS {
+				scope.spawn(|_| {
+					loop {
+						let key = self.repairs.pop();
+						catch_panic("repair", || { repair(self, &key); });
 					}
-				}
+				});
+			}
 
-				if flag == true {
-					req.respond(Response::empty(411)).expect("error while responding");
-					continue;
+			if self.migrate {
+				for _i in 0..REPAIR_WORKERS {
+					scope.spawn(|_| {
+						loop {
+							let key = self.migrations.pop();
+							catch_panic("migration", || { migrate(self, &key); });
+						}
+					});
 				}
+			}
+		}).expect("server: crossbeam failed");
+	}
 
-				let rec = self.get_record(&key);
-				if rec.deleted == Deleted::No {
-					req.respond(Response::empty(403)).expect("error while responding");
-					continue;
-				}
+	fn serve(&self, req: Request) {
+		let split = req.url().splitn(2, '?').map(|x| x.to_string()).collect::<Vec<String>>();
+		let key = split[0].clone();
+		let q = split.get(1).cloned().unwrap_or_default();
 
-				let kvolumes = key_to_volume(&key, &self.volumes, self.replicas, self.subvolumes);
+		let method = req.method().clone();
 
+		let mut query = HashMap::new();
 
-				self.put_record(&key, Record {rvolumes: kvolumes.clone(), deleted: Deleted::Soft, hash: "".to_string()}); // TODO: not handling errors here
+		let qs = q.split('&').filter(|x| !x.is_empty()).collect::<Vec<&str>>();
 
-				let mut buffer = Vec::<u8>::new();
-				req.as_reader().read_to_end(&mut buffer).unwrap(); // TODO: handle error
-				for i in 0..kvolumes.len() {
-					let remote = format!("http://{}{}", kvolumes[i], key_to_path(&key));
-					match remote_put(&remote, buffer.len(), &str::from_utf8(&buffer).to_owned().unwrap().to_string()) {
-						Err(_e) => flag = true,
-						Ok(_v) => flag = false,
-					}
+		qs.iter()
+			.for_each(|x| {
+			let mut v = x.splitn(2, '=');
+			query.insert(v.next().unwrap(), percent_decode(v.next().unwrap_or("")));
+		});
 
-					if flag == true { break; }
-				}
+		if !query.is_empty() {
+			if method != Method::Get {
+				req.respond(Response::empty(403)).expect("error while responding");
+				return;
+			}
 
-				if flag == true {
-					eprintln!("replica write failed");
-					req.respond(Response::empty(500)).expect("error while responding");
-					continue;
-				}
+			let operation = qs[0];
+			match operation {
+				"list" | "unlinked" => {
+					let mut limit = 0;
+
+					let qlimit = query.get("limit").map(|x| x.as_str()).unwrap_or("");
+
+					if !qlimit.is_empty() {
+						match qlimit.parse::<usize>() {
+							Ok(nlimit) => limit = nlimit,
+							Err(_e) => {
+								req.respond(Response::empty(400)).expect("error while responding");
+								return;
+							},
+						}
+					}
+
+					// Like the original, the path of the request is the prefix unless
+					// one is given explicitly.
+					let prefix = query.get("prefix").cloned().unwrap_or(key);
+					let start = query.get("start").cloned().unwrap_or_default();
 
-				let hash = format!("{}", String::from_utf8(md5::compute(buffer).0.to_vec()).unwrap());
-				self.put_record(&key, Record {rvolumes: kvolumes, deleted: Deleted::No, hash }); // TODO: not handling errors here
+					let lr = match self.list(&prefix, &start, limit, operation == "unlinked") {
+						Some(lr) => lr,
+						None => {
+							req.respond(Response::empty(413)).expect("error while responding");
+							return;
+						}
+					};
 
-				req.respond(Response::empty(201)).expect("error while responding");
-			} else if method == &Method::Delete || method == method_unlink {
-				let unlink = method == method_unlink;
+					let lsw = match serde_json::to_string(&lr) {
+						Ok(v) => v,
+						Err(_e) => {
+							req.respond(Response::empty(500)).expect("error while responding");
+							return;
+						}
+					};
 
-				let rec = self.get_record(&key);
+					let headers = vec![Header::from_bytes(&b"Content-Type"[..], &b"application/json"[..]).unwrap()];
 
-				if rec.deleted == Deleted::Hard || (unlink && rec.deleted == Deleted::Soft) {
-					req.respond(Response::empty(404)).expect("error while responding");
+					let bytes = lsw.as_bytes();
+					let resp = Response::new(200.into(), headers, bytes, Some(bytes.len()), None);
+					req.respond(resp).expect("error while responding");
 					return;
 				}
-				
-				if !unlink && self.protect && rec.deleted == Deleted::No {
+				_ => {
 					req.respond(Response::empty(403)).expect("error while responding");
 					return;
 				}
+			}
+		} // end query handler
 
-				self.put_record(&key, Record {
-					rvolumes: rec.rvolumes.clone(), 
-					deleted: Deleted::Soft,
-					hash: rec.hash
-				});
+		
+		if method == Method::Put || method == Method::Delete || method == method_unlink() || method == method_rebalance() {
+			let _guard = match self.lock_key(&key) {
+				Some(guard) => guard,
+				None => {
+					req.respond(Response::empty(409)).expect("error while responding");
+					return;
+				}
+			};
 
-				if !unlink {
-					let mut delete_error = false;
-					for volume in rec.rvolumes {
-						let remote = format!("http://{}{}", volume, key_to_path(&key));
+			self.write(req, &key, &method);
+			return;
+		}
 
-						match remote_delete(remote) {
-							Err(_e) => delete_error = true,
-							Ok(_v) => {},
-						}
-					}
+		if method == Method::Get || method == Method::Head {
+			let rec = self.get_record(&key);
+			
+			let mut remote = String::new();
+			let mut resp = Response::empty(404);
 
-					if delete_error {
-						req.respond(Response::empty(500)).expect("error while responding");
-						return;
-					}
+			if let Some(digest) = &rec.hash {
+				let (name, value) = digest.header();
+				resp.add_header(Header::from_bytes(name.as_bytes(), value).unwrap());
+			}
 
-					self.db.remove(&key);
+			if rec.deleted == Deleted::Soft || rec.deleted == Deleted::Hard {
+				if self.fallback.is_empty() {
+					let header = Header::from_bytes(&b"Content-Length"[..], &b"0"[..]).unwrap();
+					resp.add_header(header);
+					resp = Response::with_status_code(resp, 404);
+					req.respond(resp).expect("error while responding");
+					return;
 				}
 
-				req.respond(Response::empty(204)).expect("error while responding");
-			} else if method == method_rebalance {
-				let rec = self.get_record(&key);
+				// Not here, maybe the cluster we sit in front of has it. What
+				// it had, if anything, is not ours to vouch for.
+				remote = format!("http://{}{}", self.fallback, key);
+				resp = Response::empty(404);
+
+				if self.migrate {
+					self.migrations.push(&key);
+				}
 
-				if rec.deleted != Deleted::No {
-					req.respond(Response::empty(404)).expect("error while responding");
+				if self.proxy {
+					self.serve_proxy(req, &[remote], None);
 					return;
 				}
+			} else {
+				let kvolumes = self.placement(&key, &rec);
 
-				let kvolumes = key_to_volume(&key, &self.volumes, self.replicas, self.subvolumes);
-				let rbreq = RebalanceRequest { key, volumes: rec.rvolumes, kvolumes };
+				if needs_rebalance(&rec.rvolumes, &kvolumes, &self.volumes) {
+					eprintln!("On wrong volumes, needs rebalance");
+				}
 
-				if !rebalance(self, &rbreq) {
-					req.respond(Response::empty(400)).expect("error while responding");
+				if let Some(layout) = rec.erasure {
+					self.serve_shards(req, &key, &rec, layout, resp);
 					return;
 				}
 
-				req.respond(Response::empty(204)).expect("error while responding");
-			}
-		} // loop
-	} // fn
-}
+				let mut degraded = rec.rvolumes.len() < self.replicas as usize;
 
+				if self.proxy {
+					if self.serve_proxy(req, &remotes(&key, &rec), rec.hash.as_ref()).map(|d| d || degraded).unwrap_or(false) {
+						self.enqueue_repair(&key);
+					}
+					return;
+				}
 
-pub fn rebuild(that: &mut Minikeyvalue, vol: &str, name: &str) -> bool {
-	let mut buf = Vec::new();
-	buf.resize((name.len() + 3) / 12, 0);
-	let bytes_decoded = match base64::decode_config_slice(name, base64::STANDARD, &mut buf) {
-		Ok(v) => v,
-		Err(e) => {
-			eprintln!("rebuild: base64 decode error: {}", e);
-			return false;
-		}
-	};
+				let mut good = false;
 
-	buf.resize(bytes_decoded, 0);							
-	let key = std::str::from_utf8(&buf).expect("rebuild: cannot unwrap buffer");
-	let kvolumes = key_to_volume(key, &that.volumes, that.replicas, that.subvolumes);
+				for r in remotes(&key, &rec) {
+					remote = r;
 
-	if !that.lock_key(key) {
-		eprintln!("rebuild: lock key issue");
-		return false;
+					if remote_head(&remote) {
+						good = true;
+						break;
+					}
+
+					degraded = true;
+				}
+
+				// Heal it from the replica that is there.
+				if good && degraded {
+					self.enqueue_repair(&key);
+				}
+
+				if !good {
+					let header = Header::from_bytes(&b"Content-Length"[..], &b"0"[..]).unwrap();
+					resp.add_header(header);
+					resp = Response::with_status_code(resp, 404);
+					req.respond(resp).expect("error while responding");
+					return;
+				}
+			}
+
+			resp = Response::with_header(resp, Header::from_bytes(&b"Location"[..], remote).unwrap());
+			resp = Response::with_header(resp, Header::from_bytes(&b"Content-Length"[..], &b"0"[..]).unwrap());
+			resp = Response::with_status_code(resp, 302);
+
+			req.respond(resp).expect("error while responding");
+		} else {
+			req.respond(Response::empty(405)).expect("error while responding");
+		}
 	}
 
-	that.unlock_key(key);
+	// Erasure coded blobs have nothing to redirect to, they are put back
+	// together here and streamed to the client.
+	fn serve_shards(&self, req: Request, key: &str, rec: &Record, layout: Layout, mut resp: Response<io::Empty>) {
+		let urls = remotes(key, rec);
+		let accept_ranges = || Header::from_bytes(&b"Accept-Ranges"[..], &b"bytes"[..]).unwrap();
 
-	let rec = match that.db.get(key) {
-		Some(v) => {
-			Record::from(v.clone())
+		let etag = rec.hash.as_ref().map(|d| format!("\"{}\"", d.hex()));
+		let etag_header = |etag: &String| Header::from_bytes(&b"ETag"[..], etag.as_bytes()).unwrap();
+
+		resp.add_header(accept_ranges());
+		if let Some(etag) = &etag {
+			resp.add_header(etag_header(etag));
 		}
-		None => {
-			Record {
-				rvolumes: vec![vol.to_string()],
-				deleted: Deleted::No,
-				hash: String::new(),
+
+		// The size of the blob is in the layout, so a range can be worked out
+		// before any shard is read.
+		let mut ranged = None;
+
+		if let (Some(range), true) = (range_header(&req, etag.as_ref()), req.method() == &Method::Get) {
+			match parse_range(&range, layout.size) {
+				Ok(r) => ranged = r,
+				Err(()) => {
+					let header = Header::from_bytes(&b"Content-Range"[..], format!("bytes */{}", layout.size)).unwrap();
+					req.respond(Response::with_header(resp, header).with_status_code(416)).expect("error while responding");
+					return;
+				},
 			}
 		}
-	};
 
-	let mut pvalues = Vec::<String>::new();
-	for v in &kvolumes {
-		for v2 in &rec.rvolumes {
-			if *v == *v2 {
-				pvalues.push(v.to_string());
+		let body: Result<Box<dyn Read + Send>, io::Error> = if req.method() == &Method::Head {
+			let available = urls.iter().filter(|u| !u.is_empty() && remote_head(u)).count();
+
+			if available >= layout.codec.data {
+				if available < urls.len() { self.enqueue_repair(key); }
+				Ok(Box::new(io::empty()))
+			} else {
+				Err(io::Error::new(io::ErrorKind::NotFound, "not enough shards left to decode"))
 			}
+		} else {
+			let reader = match ranged {
+				Some((start, end)) => ShardReader::open_range(&urls, layout, start, end),
+				None => ShardReader::open(&urls, layout),
+			};
+
+			reader.map(|r| {
+				if r.degraded() { self.enqueue_repair(key); }
+				Box::new(r) as Box<dyn Read + Send>
+			})
+		};
+
+		match body {
+			Ok(body) => {
+				let (resp, length) = match ranged {
+					// The digest is of the whole blob, so the part gets a
+					// response of its own without it.
+					Some((start, end)) => {
+						let mut part = Response::empty(206).with_header(accept_ranges());
+						if let Some(etag) = &etag {
+							part.add_header(etag_header(etag));
+						}
+
+						let header = Header::from_bytes(&b"Content-Range"[..], format!("bytes {}-{}/{}", start, end - 1, layout.size)).unwrap();
+						(part.with_header(header), end - start)
+					},
+					None => (Response::with_status_code(resp, 200), layout.size),
+				};
+
+				// Known length, so no need for chunked encoding however large it is.
+				let resp = resp
+					.with_data(body, Some(length as usize))
+					.with_chunked_threshold(usize::MAX);
+				respond_stream(req, resp, key);
+			},
+			Err(e) => {
+				eprintln!("cannot read {}: {}", key, e);
+
+				let header = Header::from_bytes(&b"Content-Length"[..], &b"0"[..]).unwrap();
+				req.respond(Response::with_header(resp, header).with_status_code(404)).expect("error while responding");
+			},
 		}
 	}
 
-	for v2 in &rec.rvolumes {
-		let mut insert = true;
-		
-		for v in &kvolumes {
-			if *v == *v2 {
-				insert = false;
+	// Streams the blob from the first of `urls` that has it instead of
+	// redirecting the client there, or the part of it the client asked for
+	// with `Range`. Returns whether a replica had to be skipped, `None` if none
+	// could be read at all.
+	fn serve_proxy(&self, req: Request, urls: &[String], digest: Option<&Digest>) -> Option<bool> {
+		let mut resp = Response::empty(404).with_header(Header::from_bytes(&b"Accept-Ranges"[..], &b"bytes"[..]).unwrap());
+
+		let etag = digest.map(|d| format!("\"{}\"", d.hex()));
+		if let Some(etag) = &etag {
+			resp.add_header(Header::from_bytes(&b"ETag"[..], etag.as_bytes()).unwrap());
+		}
+
+		// Where the range lies has to be worked out against the size of the
+		// blob before any of it is read.
+		let mut ranged = None;
+
+		if let (Some(range), true) = (range_header(&req, etag.as_ref()), req.method() == &Method::Get) {
+			if let Some(size) = urls.iter().find_map(|u| remote_size(u)) {
+				match parse_range(&range, size) {
+					Ok(r) => ranged = r.map(|(start, end)| (start, end, size)),
+					Err(()) => {
+						let header = Header::from_bytes(&b"Content-Range"[..], format!("bytes */{}", size)).unwrap();
+						req.respond(Response::with_header(resp, header).with_status_code(416)).expect("error while responding");
+						return Some(false);
+					},
+				}
+			}
+		}
+
+		let body: Result<(Box<dyn Read + Send>, u64, bool), io::Error> = if req.method() == &Method::Head {
+			urls.iter().enumerate()
+				.find_map(|(i, u)| remote_size(u).map(|size| (Box::new(io::empty()) as Box<dyn Read + Send>, size, i > 0)))
+				.ok_or_else(|| io::Error::new(io::ErrorKind::NotFound, "no replica left to read"))
+		} else {
+			FailoverReader::open(urls, ranged.map(|(start, end, _)| (start, end))).map(|r| {
+				let (length, degraded) = (r.remaining(), r.degraded());
+				(Box::new(r) as Box<dyn Read + Send>, length, degraded)
+			})
+		};
+		match body {
+			Ok((body, length, degraded)) => {
+				let status = match ranged {
+					Some((start, end, size)) => {
+						let header = Header::from_bytes(&b"Content-Range"[..], format!("bytes {}-{}/{}", start, end - 1, size)).unwrap();
+						resp.add_header(header);
+						206
+					},
+					None => {
+						// The digest is of the whole blob, so it only goes with all of it.
+						if let Some(digest) = digest {
+							let (name, value) = digest.header();
+							resp.add_header(Header::from_bytes(name.as_bytes(), value).unwrap());
+						}
+
+						200
+					},
+				};
+
+				let resp = Response::with_status_code(resp, status)
+					.with_data(body, Some(length as usize))
+					.with_chunked_threshold(usize::MAX);
+
+				// Every replica can fail halfway through the body.
+				let url = req.url().to_string();
+				respond_stream(req, resp, &url);
+				Some(degraded)
+			},
+			Err(e) => {
+				eprintln!("cannot read {}: {}", req.url(), e);
+
+				let header = Header::from_bytes(&b"Content-Length"[..], &b"0"[..]).unwrap();
+				req.respond(Response::with_header(resp, header).with_status_code(404)).expect("error while responding");
+				None
+			},
+		}
+	}
+
+	// PUT, DELETE, UNLINK and REBALANCE, called with `key` locked.
+	fn write(&self, mut req: Request, key: &str, method: &Method) {
+		if method == &Method::Put {
+			let mut flag = false;
+			for head in &req.headers().to_vec() {
+				if head.field.as_str() == "Content-Length" && head.value == "0" {
+					flag = true;	
+				}
+			}
+
+			if flag {
+				req.respond(Response::empty(411)).expect("error while responding");
+				return;
+			}
+
+			// Checked once the whole body went through, a bad header is refused
+			// before anything is written.
+			let expected = match req.headers().iter().find(|h| h.field.equiv("Content-MD5")) {
+				Some(h) => match decode_md5(h.value.as_str()) {
+					Some(d) => Some(d),
+					None => {
+						req.respond(Response::empty(400)).expect("error while responding");
+						return;
+					}
+				},
+				None => None,
+			};
+
+			let rec = self.get_record(key);
+			if rec.deleted == Deleted::No {
+				req.respond(Response::empty(403)).expect("error while responding");
+				return;
+			}
+
+			// An unlinked key keeps its record until it is written again for good.
+			let previous = self.db.get(key);
+
+			let length = req.body_length().map(|l| l as u64);
+
+			let count = self.erasure.map(|c| c.shards() as i32).unwrap_or(self.replicas);
+			let kvolumes = key_to_volume(key, &self.volumes, count, self.subvolumes);
+
+			let soft = Record {
+				rvolumes: kvolumes.clone(),
+				deleted: Deleted::Soft,
+				hash: None,
+				erasure: self.erasure.map(|c| Layout::new(c, length)),
+			};
+			let remotes = remotes(key, &soft);
+
+			if let Err(e) = self.put_record(key, soft) {
+				eprintln!("put_record error: {}", e);
+				req.respond(Response::empty(500)).expect("error while responding");
+				return;
+			}
+
+			// The body is hashed on its way through to the volumes, with MD5 on
+			// top of the configured algorithm if the client sent one to check.
+			let mut algorithms = vec![self.algorithm];
+			if expected.is_some() && self.algorithm != Algorithm::Md5 {
+				algorithms.push(Algorithm::Md5);
+			}
+
+			let mut body = DigestReader::new(req.as_reader(), &algorithms);
+			let (results, layout) = match self.erasure {
+				Some(codec) => {
+					let (results, layout) = erasure::put_shards(&remotes, codec, length, &mut body);
+					(results, Some(layout))
+				},
+				None => (remote_put_all(&remotes, length, &mut body), None),
+			};
+			let digests = body.finish();
+
+			for (remote, result) in remotes.iter().zip(results.iter()) {
+				if let Err(e) = result {
+					eprintln!("replica write to {} failed: {}", remote, e);
+				}
+			}
+
+			let acked = results.iter().filter(|r| r.is_ok()).count();
+
+			// Too few copies to call it stored, take back the ones that made it.
+			if acked < self.write_quorum {
+				eprintln!("{} of {} replicas written for {}, below quorum", acked, remotes.len(), key);
+
+				for (remote, result) in remotes.iter().zip(results.iter()) {
+					if result.is_ok() {
+						if let Err(e) = remote_delete(remote.to_string()) {
+							eprintln!("delete error: {}", e);
+						}
+					}
+				}
+
+				self.restore_record(key, previous);
+
+				// The client still wants to know how far it got.
+				let header = Header::from_bytes(&b"X-Replicas-Acknowledged"[..], acked.to_string()).unwrap();
+				req.respond(Response::empty(500).with_header(header)).expect("error while responding");
+				return;
+			}
+
+			let md5 = digests.iter().find(|d| d.algorithm == Algorithm::Md5).map(|d| d.bytes.as_slice());
+
+			if expected.is_some() && expected.as_ref().map(|e| &e[..]) != md5 {
+				eprintln!("Content-MD5 mismatch for {}", key);
+
+				for remote in remotes {
+					if let Err(e) = remote_delete(remote) {
+						eprintln!("delete error: {}", e);
+					}
+				}
+
+				self.restore_record(key, previous);
+
+				req.respond(Response::empty(400)).expect("error while responding");
+				return;
+			}
+
+			let hash = digests.into_iter().next();
+			let rec = Record {rvolumes: kvolumes, deleted: Deleted::No, hash, erasure: layout };
+
+			if rec.erasure.is_some() {
+				let written = rec.rvolumes.iter().zip(results.iter()).filter(|(_, r)| r.is_ok()).map(|(v, _)| v);
+				put_layouts(key, written, &rec);
+			}
+
+			if let Err(e) = self.put_record(key, rec) {
+				eprintln!("put_record error: {}", e);
+				req.respond(Response::empty(500)).expect("error while responding");
+				return;
+			}
+
+			if acked < remotes.len() {
+				self.enqueue_repair(key);
+			}
+
+			let header = Header::from_bytes(&b"X-Replicas-Acknowledged"[..], acked.to_string()).unwrap();
+			req.respond(Response::empty(201).with_header(header)).expect("error while responding");
+		} else if method == &Method::Delete || method == &method_unlink() {
+			let unlink = method == &method_unlink();
+
+			let rec = self.get_record(key);
+
+			if rec.deleted == Deleted::Hard || (unlink && rec.deleted == Deleted::Soft) || rec.is_tombstone() {
+				req.respond(Response::empty(404)).expect("error while responding");
+				return;
+			}
+			
+			if !unlink && self.protect && rec.deleted == Deleted::No {
+				req.respond(Response::empty(403)).expect("error while responding");
+				return;
+			}
+
+			if let Err(e) = self.put_record(key, Record {
+				rvolumes: rec.rvolumes.clone(), 
+				deleted: Deleted::Soft,
+				hash: rec.hash.clone(),
+				erasure: rec.erasure,
+			}) {
+				eprintln!("put_record error: {}", e);
+				req.respond(Response::empty(500)).expect("error while responding");
+				return;
+			}
+
+			if !unlink {
+				if rec.erasure.is_some() {
+					delete_layouts(key, rec.rvolumes.iter());
+				}
+
+				let mut delete_error = false;
+				for remote in remotes(key, &rec).into_iter().filter(|r| !r.is_empty()) {
+					match remote_delete(remote) {
+						Err(_e) => delete_error = true,
+						Ok(_v) => {},
+					}
+				}
+
+				if delete_error {
+					req.respond(Response::empty(500)).expect("error while responding");
+					return;
+				}
+
+				// With a fallback, the record is kept to say the key is gone
+				// here and not just yet to be migrated.
+				let result = if self.fallback.is_empty() {
+					self.db.delete(key)
+				} else {
+					self.put_record(key, Record::tombstone())
+				};
+
+				if let Err(e) = result {
+					eprintln!("delete error: {}", e);
+					req.respond(Response::empty(500)).expect("error while responding");
+					return;
+				}
+			}
+
+			req.respond(Response::empty(204)).expect("error while responding");
+		} else if method == &method_rebalance() {
+			let rec = self.get_record(key);
+
+			if rec.deleted != Deleted::No {
+				req.respond(Response::empty(404)).expect("error while responding");
+				return;
+			}
+
+			let kvolumes = self.placement(key, &rec);
+			let rbreq = RebalanceRequest { key: key.to_string(), volumes: rec.rvolumes, kvolumes };
+
+			if !rebalance(self, &rbreq) {
+				req.respond(Response::empty(400)).expect("error while responding");
+				return;
+			}
+
+			req.respond(Response::empty(204)).expect("error while responding");
+		}
+	}
+}
+
+
+pub fn rebuild(that: &Minikeyvalue, vol: &str, name: &str) -> bool {
+	// Shards are named after the key with the number of the shard appended.
+	let (name, shard) = match name.split_once('.') {
+		Some((name, i)) => match i.parse::<usize>() {
+			Ok(i) => (name, Some(i)),
+			Err(_e) => {
+				eprintln!("rebuild: skipping {}", name);
+				return false;
+			}
+		},
+		None => (name, None),
+	};
+
+	let mut buf = vec![0; name.len().div_ceil(4) * 3];
+	let bytes_decoded = match base64::decode_config_slice(name, base64::STANDARD, &mut buf) {
+		Ok(v) => v,
+		Err(e) => {
+			eprintln!("rebuild: base64 decode error: {}", e);
+			return false;
+		}
+	};
+
+	buf.resize(bytes_decoded, 0);
+
+	let key = match str::from_utf8(&buf) {
+		Ok(key) => key,
+		Err(e) => {
+			eprintln!("rebuild: {} is not a key: {}", name, e);
+			return false;
+		}
+	};
+
+	if let Some(i) = shard {
+		return rebuild_shard(that, vol, key, i);
+	}
+
+	let kvolumes = key_to_volume(key, &that.volumes, that.replicas, that.subvolumes);
+
+	// Other workers may have found a replica of the same key, wait for them
+	// instead of dropping this one.
+	let _guard = that.lock_key_wait(key);
+
+	let mut rec = match that.db.get(key) {
+		Some(v) => {
+			Record::from(v)
+		}
+		None => {
+			Record {
+				rvolumes: vec![],
+				deleted: Deleted::No,
+				hash: None,
+				erasure: None,
+			}
+		}
+	};
+
+	if !rec.rvolumes.iter().any(|v| v == vol) {
+		rec.rvolumes.push(vol.to_string());
+	}
+
+	let mut pvalues = Vec::<String>::new();
+	for v in &kvolumes {
+		for v2 in &rec.rvolumes {
+			if *v == *v2 {
+				pvalues.push(v.to_string());
+			}
+		}
+	}
+
+	for v2 in &rec.rvolumes {
+		let mut insert = true;
+		
+		for v in &kvolumes {
+			if *v == *v2 {
+				insert = false;
 				break;
 			}
 		}
@@ -537,16 +1301,75 @@ pub fn rebuild(that: &mut Minikeyvalue, vol: &str, name: &str) -> bool {
 		if insert { pvalues.push(v2.to_string()); }
 	}
 
-	that.put_record(key, Record {
+	if let Err(e) = that.put_record(key, Record {
 		rvolumes: pvalues,
 		deleted: Deleted::No,
-		hash: String::new(),
-	});
+		hash: rec.hash,
+		erasure: None,
+	}) {
+		eprintln!("rebuild: put_record error: {}", e);
+		return false;
+	}
 	
 	true
 }
 
-pub fn rebalance(that: &mut Minikeyvalue, req: &RebalanceRequest) -> bool {
+// Puts shard `i` of `key`, found on `vol`, into its record, which is started
+// from the layout and digest kept next to the shard if it isn't there yet.
+fn rebuild_shard(that: &Minikeyvalue, vol: &str, key: &str, i: usize) -> bool {
+	let stored = remote_get(&erasure::layout_url(vol, key)).ok()
+		.and_then(|b| String::from_utf8(b).ok())
+		.map(Record::from)
+		.filter(|r| r.erasure.map(|l| i < l.codec.shards()).unwrap_or(false));
+
+	let stored = match stored {
+		Some(stored) => stored,
+		None => {
+			eprintln!("rebuild: no layout next to shard {} of {} on {}", i, key, vol);
+			return false;
+		}
+	};
+
+	let _guard = that.lock_key_wait(key);
+
+	let mut rec = match that.db.get(key) {
+		Some(v) => Record::from(v),
+		None => Record {
+			rvolumes: vec![String::new(); stored.erasure.unwrap().codec.shards()],
+			deleted: Deleted::No,
+			hash: stored.hash,
+			erasure: stored.erasure,
+		},
+	};
+
+	// Left over from an earlier blob under the same key.
+	if rec.erasure != stored.erasure || rec.rvolumes.len() <= i {
+		eprintln!("rebuild: shard {} of {} on {} does not belong with the others", i, key, vol);
+		return false;
+	}
+
+	rec.rvolumes[i] = vol.to_string();
+
+	if let Err(e) = that.put_record(key, rec) {
+		eprintln!("rebuild: put_record error: {}", e);
+		return false;
+	}
+
+	true
+}
+
+// Moves `key` from the volumes it is on to `req.kvolumes`, without ever
+// leaving fewer good copies than it had: the new copies are written and read
+// back first, then the record is switched over, and only then are the copies
+// that are no longer wanted deleted.
+pub fn rebalance(that: &Minikeyvalue, req: &RebalanceRequest) -> bool {
+	let rec = that.get_record(&req.key);
+
+	// Written or deleted since the request was made, nothing left to move.
+	if rec.deleted != Deleted::No || rec.rvolumes != req.volumes { return true; }
+
+	if rec.erasure.is_some() { return rebalance_shards(that, req, rec); }
+
 	let kp = key_to_path(&req.key);
 
 	let mut rvolumes = Vec::<String>::new();
@@ -561,86 +1384,640 @@ pub fn rebalance(that: &mut Minikeyvalue, req: &RebalanceRequest) -> bool {
 		return false;
 	}
 
-	if !needs_rebalance(&rvolumes, &req.kvolumes) { return true; }
+	if !needs_rebalance(&rvolumes, &req.kvolumes, &that.volumes) { return true; }
 
-	let s = match remote_get(&format!("http://{}{}", &rvolumes[0], kp)) {
-		Ok(ss) => ss,
-		Err(_e) => return false,
-	};
+	let added = req.kvolumes.iter().filter(|v| !rvolumes.contains(v)).collect::<Vec<&String>>();
+	let targets = added.iter().map(|v| format!("http://{}{}", v, kp)).collect::<Vec<String>>();
 
-	for v in req.kvolumes.iter() {
-		let mut needs_write = true;
+	if !targets.is_empty() {
+		let algorithm = rec.hash.as_ref().map(|d| d.algorithm).unwrap_or(that.algorithm);
 
-		for v2 in rvolumes.iter() {
-			if v == v2 {
-				needs_write = false;
-				break;
+		// Any replica will do as the source, as long as it is the blob the
+		// record says it is.
+		let copied = rvolumes.iter().any(|rv| {
+			match copy_verified(&format!("http://{}{}", rv, kp), &targets, algorithm, rec.hash.as_ref()) {
+				Ok(()) => true,
+				Err(e) => {
+					eprintln!("rebalance: {}", e);
+					false
+				}
 			}
+		});
+
+		if !copied {
+			// Don't leave half a move behind, but never touch a copy the
+			// record still counts on.
+			for (v, target) in added.iter().zip(targets.iter()) {
+				if !rec.rvolumes.contains(v) {
+					let _ = remote_delete(target.clone());
+				}
+			}
+
+			return false;
+		}
+	}
+
+	if let Err(e) = that.put_record(&req.key, Record {
+		rvolumes: req.kvolumes.clone(),
+		deleted: Deleted::No,
+		hash: rec.hash,
+		erasure: None,
+	}) {
+		eprintln!("rebalance: put_record error: {}", e);
+		return false;
+	}
+
+	for v in rvolumes.iter().filter(|v| !req.kvolumes.contains(v)) {
+		if let Err(e) = remote_delete(format!("http://{}{}", v, kp)) {
+			eprintln!("delete error: {}", e);
 		}
+	}
+
+	true
+}
+
+// Writes the copies of `key` its record lists but the volumes don't have,
+// from one that is still there. Shards are decoded from the others. A record
+// short of volumes, say after a rebuild that found only some of the copies,
+// is topped up from its placement and updated.
+pub fn repair(that: &Minikeyvalue, key: &str) -> bool {
+	let _guard = that.lock_key_wait(key);
+
+	let rec = that.get_record(key);
+	if rec.deleted != Deleted::No { return true; }
+
+	let placement = that.placement(key, &rec);
+	let mut rvolumes = rec.rvolumes.clone();
+
+	match rec.erasure {
+		Some(_) => {
+			for (v, p) in rvolumes.iter_mut().zip(placement) {
+				if v.is_empty() { *v = p; }
+			}
+		},
+		None => {
+			for p in placement {
+				if rvolumes.len() >= that.replicas as usize { break; }
+				if !rvolumes.contains(&p) { rvolumes.push(p); }
+			}
+		},
+	}
 
-		if needs_write {
-			if let Err(e) = remote_put(&format!("http://{}{}", v, kp), s.len(), &s) {
-				eprintln!("put error: {}", e);
+	let changed = rvolumes != rec.rvolumes;
+	let rec = Record { rvolumes, deleted: Deleted::No, hash: rec.hash, erasure: rec.erasure };
+
+	let urls = remotes(key, &rec);
+	let missing = (0..urls.len())
+		.filter(|&i| !urls[i].is_empty() && !remote_head(&urls[i]))
+		.collect::<Vec<usize>>();
+
+	if missing.is_empty() { return true; }
+
+	let results = match rec.erasure {
+		Some(layout) => match erasure::repair_shards(&urls, &missing, layout) {
+			Ok(results) => results,
+			Err(e) => {
+				eprintln!("repair: cannot read {}: {}", key, e);
 				return false;
 			}
+		},
+		None => {
+			let source = match urls.iter().enumerate().find(|(i, u)| !missing.contains(i) && !u.is_empty()) {
+				Some((_, u)) => u,
+				None => {
+					eprintln!("repair: no replica of {} left to copy", key);
+					return false;
+				}
+			};
+
+			let targets = missing.iter().map(|&i| urls[i].clone()).collect::<Vec<String>>();
+			copy_blob(source, &targets)
+		},
+	};
+
+	let mut repaired = true;
+	let mut rewritten = Vec::new();
+
+	for (i, result) in missing.iter().zip(results) {
+		match result {
+			Ok(()) => rewritten.push(&rec.rvolumes[*i]),
+			Err(e) => {
+				eprintln!("repair: write to {} failed: {}", urls[*i], e);
+				repaired = false;
+			},
 		}
 	}
 
-	that.put_record(&req.key, Record {
-		rvolumes: req.kvolumes.clone(),
+	if rec.erasure.is_some() {
+		put_layouts(key, rewritten, &rec);
+	}
+
+	if repaired && changed {
+		if let Err(e) = that.put_record(key, rec) {
+			eprintln!("repair: put_record error: {}", e);
+			return false;
+		}
+	}
+
+	repaired
+}
+
+// Copies `key` over from the fallback server, so a migration from the cluster
+// behind it finishes itself as keys are read. Keys this cluster has heard of,
+// even deleted ones by their tombstone, are left alone.
+pub fn migrate(that: &Minikeyvalue, key: &str) -> bool {
+	let _guard = that.lock_key_wait(key);
+	if that.db.get(key).is_some() { return true; }
+
+	let src = match remote_open(&format!("http://{}{}", that.fallback, key)) {
+		Ok(resp) => resp,
+		Err(e) => {
+			eprintln!("migrate: cannot read {} from the fallback: {}", key, e);
+			return false;
+		}
+	};
+
+	let length = src.content_length();
+	let count = that.erasure.map(|c| c.shards() as i32).unwrap_or(that.replicas);
+
+	let mut rec = Record {
+		rvolumes: key_to_volume(key, &that.volumes, count, that.subvolumes),
 		deleted: Deleted::No,
-		hash: String::new(),			
-	});
+		hash: None,
+		erasure: that.erasure.map(|c| Layout::new(c, length)),
+	};
+	let urls = remotes(key, &rec);
+
+	let mut body = DigestReader::new(src, &[that.algorithm]);
+	let results = match that.erasure {
+		Some(codec) => {
+			let (results, layout) = erasure::put_shards(&urls, codec, length, &mut body);
+			rec.erasure = Some(layout);
+			results
+		},
+		None => remote_put_all(&urls, length, &mut body),
+	};
 
-	for v2 in rvolumes.iter() {
-		let mut needs_delete = true;
+	// All of it or nothing, there is no hurry and the fallback still has it.
+	if results.iter().any(|r| r.is_err()) {
+		for (url, result) in urls.into_iter().zip(results) {
+			match result {
+				Ok(()) => { let _ = remote_delete(url); },
+				Err(e) => eprintln!("migrate: write to {} failed: {}", url, e),
+			}
+		}
 
-		for v in req.kvolumes.iter() {
-			if v == v2 {
-				needs_delete = true;
-				break;
+		return false;
+	}
+
+	rec.hash = body.finish().into_iter().next();
+
+	if rec.erasure.is_some() {
+		put_layouts(key, rec.rvolumes.iter(), &rec);
+	}
+
+	if let Err(e) = that.put_record(key, rec) {
+		eprintln!("migrate: put_record error: {}", e);
+		return false;
+	}
+
+	true
+}
+
+// Checks every copy of `key` and, with `--repair`, rewrites the bad ones.
+fn scrub(that: &Minikeyvalue, key: &str, rec: &Record, budget: &mut Budget, report: &mut Report) {
+	let urls = remotes(key, rec);
+	report.keys += 1;
+
+	if let Some(layout) = rec.erasure {
+		let statuses = scrub::check_each_shard(&urls, layout, rec.hash.as_ref(), budget);
+
+		for (i, status) in statuses.iter().enumerate() {
+			match status {
+				Status::Missing => report.missing += 1,
+				Status::Corrupt => report.corrupt += 1,
+				Status::Good => continue,
 			}
+
+			eprintln!("scrub: shard {} of {} at {} is {:?}", i, key, urls[i], status);
 		}
 
-		if needs_delete {
-			if let Err(e) = remote_delete(format!("http://{}{}", v2, kp)) {
-				eprintln!("delete error: {}", e);
+		if !that.scrub_repair { return; }
+
+		let bad = (0..urls.len())
+			.filter(|&i| statuses[i] != Status::Good && !urls[i].is_empty())
+			.collect::<Vec<usize>>();
+
+		if !bad.is_empty() {
+			if statuses.iter().filter(|s| **s == Status::Good).count() < layout.codec.data {
+				eprintln!("scrub: not enough good shards of {} left to repair", key);
+				return;
+			}
+
+			// Only if nobody wrote the key since it was checked.
+			let _guard = that.lock_key_wait(key);
+			let now = that.get_record(key);
+
+			if now.deleted != Deleted::No || now.rvolumes != rec.rvolumes || now.hash != rec.hash { return; }
+
+			match erasure::repair_shards(&urls, &bad, layout) {
+				Ok(results) => {
+					let mut rewritten = Vec::new();
+
+					for (i, result) in bad.iter().zip(results) {
+						match result {
+							Ok(()) => {
+								report.repaired += 1;
+								rewritten.push(&rec.rvolumes[*i]);
+							},
+							Err(e) => eprintln!("scrub: repair of {} failed: {}", urls[*i], e),
+						}
+					}
+
+					put_layouts(key, rewritten, rec);
+				},
+				Err(e) => eprintln!("scrub: cannot read {}: {}", key, e),
+			}
+		}
+
+		// Shards that were never placed anywhere.
+		let unplaced = urls.iter().filter(|u| u.is_empty()).count();
+		if unplaced > 0 && repair(that, key) {
+			report.repaired += unplaced as u64;
+		}
+
+		return;
+	}
+
+	let statuses = urls.iter().map(|u| scrub::check(u, rec.hash.as_ref(), budget)).collect::<Vec<Status>>();
+
+	for (url, status) in urls.iter().zip(statuses.iter()) {
+		match status {
+			Status::Missing => report.missing += 1,
+			Status::Corrupt => report.corrupt += 1,
+			Status::Good => continue,
+		}
+
+		eprintln!("scrub: {} of {} is {:?}", url, key, status);
+	}
+
+	let source = match urls.iter().zip(statuses.iter()).find(|(_, s)| **s == Status::Good) {
+		Some((u, _)) => u,
+		None => {
+			if !urls.is_empty() { eprintln!("scrub: no good replica of {} left", key); }
+			return;
+		}
+	};
+
+	let targets = urls.iter().zip(statuses.iter())
+		.filter(|(_, s)| **s != Status::Good)
+		.map(|(u, _)| u.clone())
+		.collect::<Vec<String>>();
+
+	if targets.is_empty() || !that.scrub_repair { return; }
+
+	// Only if nobody wrote the key since it was checked.
+	let _guard = that.lock_key_wait(key);
+	let now = that.get_record(key);
+
+	if now.deleted != Deleted::No || now.rvolumes != rec.rvolumes || now.hash != rec.hash { return; }
+
+	for (url, result) in targets.iter().zip(copy_blob(source, &targets)) {
+		match result {
+			Ok(()) => report.repaired += 1,
+			Err(e) => eprintln!("scrub: repair of {} failed: {}", url, e),
+		}
+	}
+}
+
+// Copies the blob at `src` over to every one of `targets`.
+fn copy_blob(src: &str, targets: &[String]) -> Vec<PutResult> {
+	match remote_open(src) {
+		Ok(mut resp) => {
+			let length = resp.content_length();
+			remote_put_all(targets, length, &mut resp)
+		},
+		Err(e) => targets.iter().map(|_| Err(format!("cannot read {}: {}", src, e).into())).collect(),
+	}
+}
+
+// Copies the blob at `src` over to every one of `targets` and reads each copy
+// back to check it is what was read from `src`, and `expected` if given.
+fn copy_verified(src: &str, targets: &[String], algorithm: Algorithm, expected: Option<&Digest>) -> Result<(), String> {
+	let resp = remote_open(src).map_err(|e| format!("cannot read {}: {}", src, e))?;
+	let length = resp.content_length();
+	let mut body = DigestReader::new(resp, &[algorithm]);
+
+	for (target, result) in targets.iter().zip(remote_put_all(targets, length, &mut body)) {
+		result.map_err(|e| format!("cannot write {}: {}", target, e))?;
+	}
+
+	let digest = body.finish().remove(0);
+
+	if expected.map(|d| *d != digest).unwrap_or(false) {
+		return Err(format!("{} does not match its record", src));
+	}
+
+	for target in targets {
+		if scrub::check(target, Some(&digest), &mut Budget::new(0)) != Status::Good {
+			return Err(format!("copy at {} does not match {}", target, src));
+		}
+	}
+
+	Ok(())
+}
+
+// Shards are not interchangeable like replicas: shard `i` moves from the `i`th
+// volume of the record to the `i`th target.
+fn rebalance_shards(that: &Minikeyvalue, req: &RebalanceRequest, rec: Record) -> bool {
+	if !needs_rebalance(&req.volumes, &req.kvolumes, &that.volumes) { return true; }
+
+	let moves = (0..req.kvolumes.len())
+		.filter(|&i| req.volumes.get(i) != Some(&req.kvolumes[i]))
+		.collect::<Vec<usize>>();
+
+	for &i in moves.iter() {
+		let src = match req.volumes.get(i).filter(|v| !v.is_empty()) {
+			Some(v) => erasure::shard_url(v, &req.key, i),
+			None => {
+				eprintln!("rebalance: shard {} of {} is missing", i, req.key);
 				return false;
 			}
+		};
+
+		// Shards carry no digest of their own, the copy is checked against
+		// what was read from the source.
+		let target = erasure::shard_url(&req.kvolumes[i], &req.key, i);
+
+		if let Err(e) = copy_verified(&src, &[target], that.algorithm, None) {
+			eprintln!("rebalance: shard {} of {}: {}", i, req.key, e);
+			return false;
+		}
+	}
+
+	put_layouts(&req.key, moves.iter().map(|&i| &req.kvolumes[i]), &rec);
+
+	if let Err(e) = that.put_record(&req.key, Record {
+		rvolumes: req.kvolumes.clone(),
+		deleted: Deleted::No,
+		hash: rec.hash,
+		erasure: rec.erasure,
+	}) {
+		eprintln!("rebalance: put_record error: {}", e);
+		return false;
+	}
+
+	for &i in moves.iter() {
+		if let Some(v) = req.volumes.get(i).filter(|v| !v.is_empty()) {
+			if let Err(e) = remote_delete(erasure::shard_url(v, &req.key, i)) {
+				eprintln!("delete error: {}", e);
+			}
 		}
 	}
 
+	// Only where no shard of the key is left.
+	delete_layouts(&req.key, req.volumes.iter().filter(|v| !req.kvolumes.contains(v)));
+
 	true
 }
 
-fn parse_volume(vol: String) -> Option<RebuildRequest> {
-	for i in get_files(&format!("http://{}/", vol)).0 {
-		if valid(&i) {
-			for j in get_files(&format!("http://{}/{}", vol, i.name)).0 {
-				if valid(&j) {
-					let url = format!("http://{}/{}/{}/", vol, i.name, j.name);
-					return Some(RebuildRequest { vol, url });
-				}
-			}
+// Keeps the layout and digest of an erasure coded key next to its shards on
+// `volumes`, as a record without volumes, so `rebuild` can put it back.
+fn put_layouts<'a, I: IntoIterator<Item = &'a String>>(key: &str, volumes: I, rec: &Record) {
+	let stored = String::from(Record { rvolumes: vec![], deleted: Deleted::No, hash: rec.hash.clone(), erasure: rec.erasure });
+
+	for v in volumes.into_iter().filter(|v| !v.is_empty()) {
+		let url = erasure::layout_url(v, key);
+
+		if let Err(e) = remote_put(&url, Some(stored.len() as u64), io::Cursor::new(stored.clone().into_bytes())) {
+			eprintln!("cannot write {}: {}", url, e);
 		}
 	}
+}
 
-	None
+fn delete_layouts<'a, I: IntoIterator<Item = &'a String>>(key: &str, volumes: I) {
+	for v in volumes.into_iter().filter(|v| !v.is_empty()) {
+		if let Err(e) = remote_delete(erasure::layout_url(v, key)) {
+			eprintln!("delete error: {}", e);
+		}
+	}
+}
+
+// What the rebalance of `key` would copy and delete, `None` if it is fine
+// where it is.
+fn plan_move(that: &Minikeyvalue, key: String, rec: Record) -> Option<Move> {
+	if rec.deleted != Deleted::No { return None; }
+
+	let kvolumes = that.placement(&key, &rec);
+	if !needs_rebalance(&rec.rvolumes, &kvolumes, &that.volumes) { return None; }
+
+	let (copies, deletes, size) = match rec.erasure {
+		// Shards only move between the volumes at the same position.
+		Some(layout) => {
+			let moved = (0..kvolumes.len()).filter(|&i| rec.rvolumes.get(i) != Some(&kvolumes[i])).collect::<Vec<usize>>();
+
+			let copies = moved.iter().map(|&i| kvolumes[i].clone()).collect();
+			let deletes = moved.iter().filter_map(|&i| rec.rvolumes.get(i)).filter(|v| !v.is_empty()).cloned().collect();
+
+			(copies, deletes, Some(layout.shard_size()))
+		},
+		None => {
+			let copies = kvolumes.iter().filter(|v| !rec.rvolumes.contains(v)).cloned().collect::<Vec<String>>();
+			let deletes = rec.rvolumes.iter().filter(|v| !kvolumes.contains(v)).cloned().collect();
+
+			let size = if copies.is_empty() {
+				Some(0)
+			} else {
+				remotes(&key, &rec).iter().find_map(|r| remote_size(r))
+			};
+
+			(copies, deletes, size)
+		},
+	};
+
+	let bytes = size.map(|s| s * copies.len() as u64);
+	Some(Move { key, copies, deletes, bytes })
+}
+
+// The volume server of a replica, without its subvolume.
+fn volume_of(rvolume: &str) -> String {
+	rvolume.split('/').next().unwrap().to_string()
+}
+
+// Where every copy of `key` lives, in the order of `rec.rvolumes`; empty
+// where the volume of a shard is not known.
+fn remotes(key: &str, rec: &Record) -> Vec<String> {
+	rec.rvolumes.iter().enumerate().map(|(i, v)| match rec.erasure {
+		Some(_) if v.is_empty() => String::new(),
+		Some(_) => erasure::shard_url(v, key, i),
+		None => format!("http://{}{}", v, key_to_path(key)),
+	}).collect()
+}
+
+fn method_unlink() -> Method {
+	Method::NonStandard(AsciiString::from_ascii("UNLINK").unwrap())
+}
+
+fn method_rebalance() -> Method {
+	Method::NonStandard(AsciiString::from_ascii("REBALANCE").unwrap())
+}
+
+// The top level directories of `vol`, blobs are two levels below them.
+fn parse_volume(vol: String) -> Vec<RebuildRequest> {
+	get_files(&format!("http://{}/", vol)).0.iter()
+		.filter(|f| valid(f))
+		.map(|f| RebuildRequest { vol: vol.clone(), url: format!("http://{}/{}/", vol, f.name) })
+		.collect()
 }
 
 fn decode_hex(s: &str) -> Result<Vec<u8>, DecodeHexError> {
-	if s.len() % 2 != 0 {
+	if !s.len().is_multiple_of(2) {
 		Err(DecodeHexError::OddLength)
 	} else {
 		(0..s.len()).step_by(2).map(|i| u8::from_str_radix(&s[i..i + 2], 16).map_err(|e| e.into())).collect()
 	}
 }
 
+// Decodes `%XX` escapes and `+` in a query string value, leaving malformed
+// escapes untouched.
+fn percent_decode(s: &str) -> String {
+	let bytes = s.as_bytes();
+	let mut out = Vec::with_capacity(bytes.len());
+
+	let mut i = 0;
+	while i < bytes.len() {
+		match bytes[i] {
+			b'+' => out.push(b' '),
+			b'%' if i + 2 < bytes.len() => {
+				match str::from_utf8(&bytes[i + 1..i + 3]).map(|x| u8::from_str_radix(x, 16)) {
+					Ok(Ok(b)) => { out.push(b); i += 2; },
+					_ => out.push(b'%'),
+				}
+			},
+			b => out.push(b),
+		}
+		i += 1;
+	}
+
+	String::from_utf8_lossy(&out).into_owned()
+}
+
+// Sends a response whose body is streamed from the volumes. Reading it can
+// fail after the headers are out, and then all there is left to do is log it;
+// the client gets fewer bytes than the Content-Length it was promised.
+fn respond_stream<R: Read>(req: Request, resp: Response<R>, key: &str) {
+	if let Err(e) = req.respond(resp) {
+		eprintln!("cannot send {}: {}", key, e);
+	}
+}
+
+// Runs `f` on a thread of the server, which has to outlive whatever went
+// wrong in it: a panic is logged and the thread carries on with the next job.
+fn catch_panic<F: FnOnce()>(what: &str, f: F) {
+	if panic::catch_unwind(AssertUnwindSafe(f)).is_err() {
+		eprintln!("server: {} panicked, carrying on", what);
+	}
+}
+
+// Opens a listener. A Unix socket left behind by an earlier run is in the way
+// of binding to its path again, so it is removed first; any other file there
+// is not ours to remove.
+fn bind(listen: &Listen) -> Server {
+	let server = match listen {
+		Listen::Tcp(addr) => Server::http(addr),
+		Listen::Unix(path) => {
+			if fs::symlink_metadata(path).map(|m| m.file_type().is_socket()).unwrap_or(false) {
+				let _ = fs::remove_file(path);
+			}
+
+			Server::http_unix(path)
+		},
+	};
+
+	server.unwrap_or_else(|e| panic!("cannot listen on {}: {}", listen, e))
+}
+
+// Value of the request header `name`, if it was sent.
+fn header(req: &Request, name: &'static str) -> Option<String> {
+	req.headers().iter().find(|h| h.field.equiv(name)).map(|h| h.value.to_string())
+}
+
+// The `Range` asked for, if any. With `If-Range`, only the part of the blob
+// the client already has some of, going by its ETag, will do; anything else
+// gets all of it.
+fn range_header(req: &Request, etag: Option<&String>) -> Option<String> {
+	header(req, "Range").filter(|_| match header(req, "If-Range") {
+		Some(v) => Some(&v) == etag,
+		None => true,
+	})
+}
+
+// Byte range `[start, end)` asked for by a `Range` header on a blob of `size`
+// bytes. Only a single range is served, anything else is `None` and gets the
+// whole blob, as RFC 7233 allows. A range with nothing of the blob in it is
+// `Err`, and so is any range of an empty blob.
+fn parse_range(value: &str, size: u64) -> Result<Option<(u64, u64)>, ()> {
+	let spec = match value.trim().strip_prefix("bytes=") {
+		Some(spec) if !spec.contains(',') => spec.trim(),
+		_ => return Ok(None),
+	};
+
+	let (first, last) = match spec.split_once('-') {
+		Some(parts) => parts,
+		None => return Ok(None),
+	};
+
+	// `bytes=-N` is the last N bytes.
+	let (start, end) = if first.is_empty() {
+		match last.parse::<u64>() {
+			Ok(n) => (size.saturating_sub(n), size),
+			Err(_e) => return Ok(None),
+		}
+	} else {
+		let start = match first.parse::<u64>() {
+			Ok(start) => start,
+			Err(_e) => return Ok(None),
+		};
+
+		let end = match last {
+			"" => size,
+			last => match last.parse::<u64>() {
+				Ok(last) => size.min(last.saturating_add(1)),
+				Err(_e) => return Ok(None),
+			},
+		};
+
+		(start, end)
+	};
+
+	if size == 0 || start >= end { return Err(()); }
+
+	Ok(Some((start, end)))
+}
+
+// Content-MD5 is base64 per RFC 1864, hex is accepted as well since that is
+// what a lot of clients send.
+fn decode_md5(value: &str) -> Option<[u8; 16]> {
+	let bytes = match value.len() {
+		32 => decode_hex(value).ok()?,
+		_ => base64::decode(value).ok()?,
+	};
+
+	let mut digest = [0u8; 16];
+	if bytes.len() != digest.len() { return None; }
+
+	digest.copy_from_slice(&bytes);
+	Some(digest)
+}
+
 fn get_files(url: &str) -> FileWrapper {
 	let mut res = FileWrapper::new();
 
-	match remote_get(&url.to_string()) {
-		Ok(ss) => res.0 = serde_json::from_str(&ss).expect("get_files: Cannot parse"),
+	match remote_get(url) {
+		Ok(ss) => match serde_json::from_slice(&ss) {
+			Ok(files) => res.0 = files,
+			Err(e) => eprintln!("get_files: cannot parse listing of {}: {}", url, e),
+		},
 		Err(e) => {
 			eprintln!("get_files: remote_get error {}", e);
 			return res;
@@ -661,4 +2038,356 @@ fn valid(f: &File) -> bool {
 	if decoded.len() != 1 { return false; }
 
 	true
-}
\ No newline at end of file
+}
+#[cfg(test)]
+mod tests {
+	use super::*;
+	use crate::index::MemoryStore;
+	use crate::stub::{Stubs, StubVolume};
+	use std::sync::Barrier;
+	use std::sync::atomic::AtomicUsize;
+
+	const KEY: &str = "/a";
+
+	fn test_mkv(volumes: &[&str], replicas: i32) -> Minikeyvalue {
+		Minikeyvalue::new(Box::new(MemoryStore::default()), Config {
+			volumes: volumes.iter().map(|v| v.parse::<Volume>().unwrap()).collect(),
+			fallback: String::new(),
+			replicas,
+			subvolumes: 1,
+			listen: vec![],
+			protect: false,
+			threads: 1,
+			algorithm: Algorithm::Md5,
+			erasure: None,
+			write_quorum: replicas as usize,
+			scrub_interval: None,
+			scrub_rate: 0,
+			scrub_repair: false,
+			checkpoint: None,
+			resume: false,
+			dry_run: false,
+			migrate: false,
+			proxy: false,
+		})
+	}
+
+	#[test]
+	fn second_writer_is_refused() {
+		let mkv = test_mkv(&["localhost:1"], 1);
+		let guard = mkv.lock_key("/a");
+		assert!(guard.is_some());
+
+		// What a racing PUT on another thread sees, and answers 409 for.
+		let other = mkv.clone();
+		assert!(thread::spawn(move || other.lock_key("/a").is_none()).join().unwrap());
+
+		// Other keys are not held up.
+		assert!(mkv.lock_key("/b").is_some());
+	}
+
+	#[test]
+	fn lock_is_released_on_drop() {
+		let mkv = test_mkv(&["localhost:1"], 1);
+
+		let guard = mkv.lock_key("/a");
+		assert!(mkv.lock_key("/a").is_none());
+
+		drop(guard);
+		assert!(mkv.lock_key("/a").is_some());
+	}
+
+	#[test]
+	fn racing_writers_do_not_interleave() {
+		let mkv = test_mkv(&["localhost:1"], 1);
+		let barrier = Arc::new(Barrier::new(8));
+		let inside = Arc::new(AtomicUsize::new(0));
+
+		let handles = (0..8).map(|_| {
+			let (mkv, barrier, inside) = (mkv.clone(), barrier.clone(), inside.clone());
+
+			thread::spawn(move || {
+				barrier.wait();
+
+				for _ in 0..100 {
+					if let Some(_guard) = mkv.lock_key("/a") {
+						assert_eq!(inside.fetch_add(1, Ordering::SeqCst), 0);
+						thread::yield_now();
+						inside.fetch_sub(1, Ordering::SeqCst);
+					}
+				}
+			})
+		}).collect::<Vec<_>>();
+
+		for h in handles {
+			h.join().unwrap();
+		}
+
+		assert!(mkv.lock_key("/a").is_some());
+	}
+
+	#[test]
+	fn plan_skips_deleted_keys() {
+		let mkv = test_mkv(&["127.0.0.1:2"], 1);
+		let rec = |deleted| Record { rvolumes: vec!["127.0.0.1:1".to_string()], deleted, hash: None, erasure: None };
+
+		let m = plan_move(&mkv, "/a".to_string(), rec(Deleted::No)).unwrap();
+		assert_eq!(m.copies, vec!["127.0.0.1:2"]);
+		assert_eq!(m.deletes, vec!["127.0.0.1:1"]);
+
+		assert!(plan_move(&mkv, "/a".to_string(), rec(Deleted::Soft)).is_none());
+		assert!(plan_move(&mkv, "/a".to_string(), rec(Deleted::Hard)).is_none());
+	}
+
+	// A cluster of `volumes` stubs with `KEY` stored on the first one.
+	fn stub_cluster(volumes: &[&str]) -> (Minikeyvalue, Arc<Stubs>, Vec<Arc<StubVolume>>) {
+		let stubs = Arc::new(Stubs::default());
+		let vols = volumes.iter().map(|name| stubs.volume(name)).collect::<Vec<_>>();
+
+		let addrs = vols.iter().map(|v| v.addr.as_str()).collect::<Vec<&str>>();
+		let mkv = test_mkv(&addrs, 1);
+		stubs.watch(mkv.db.clone(), KEY);
+
+		let blob = b"hello world".to_vec();
+		let mut hasher = Algorithm::Md5.hasher();
+		hasher.update(&blob);
+
+		vols[0].blobs.lock().unwrap().insert(key_to_path(KEY), blob);
+		mkv.put_record(KEY, Record { rvolumes: vec![vols[0].addr.clone()], deleted: Deleted::No, hash: Some(hasher.finish()), erasure: None }).unwrap();
+
+		(mkv, stubs, vols)
+	}
+
+	fn move_to(vols: &[Arc<StubVolume>], from: &[usize], to: &[usize]) -> RebalanceRequest {
+		RebalanceRequest {
+			key: KEY.to_string(),
+			volumes: from.iter().map(|&i| vols[i].addr.clone()).collect(),
+			kvolumes: to.iter().map(|&i| vols[i].addr.clone()).collect(),
+		}
+	}
+
+	#[test]
+	fn rebalance_copies_verifies_commits_then_deletes() {
+		let (mkv, stubs, vols) = stub_cluster(&["v0", "v1"]);
+
+		assert!(rebalance(&mkv, &move_to(&vols, &[0], &[1])));
+
+		assert_eq!(*stubs.log.lock().unwrap(), vec![
+			"HEAD v0 [v0]",   // which replicas are there
+			"GET v0 [v0]",    // copy
+			"PUT v1 [v0]",
+			"GET v1 [v0]",    // verify, before the record moves
+			"DELETE v0 [v1]", // only once the record has moved
+		]);
+
+		assert_eq!(mkv.get_record(KEY).rvolumes, vec![vols[1].addr.clone()]);
+		assert!(vols[0].blobs.lock().unwrap().is_empty());
+		assert_eq!(vols[1].blobs.lock().unwrap()[&key_to_path(KEY)], b"hello world");
+	}
+
+	#[test]
+	fn rebalance_keeps_the_old_replica_when_the_copy_fails() {
+		let (mkv, stubs, vols) = stub_cluster(&["v0", "v1"]);
+		vols[1].fail_puts.store(true, Ordering::SeqCst);
+
+		assert!(!rebalance(&mkv, &move_to(&vols, &[0], &[1])));
+
+		assert_eq!(mkv.get_record(KEY).rvolumes, vec![vols[0].addr.clone()]);
+		assert!(vols[0].blobs.lock().unwrap().contains_key(&key_to_path(KEY)));
+		assert!(!stubs.log.lock().unwrap().iter().any(|l| l.starts_with("DELETE v0")));
+	}
+
+	#[test]
+	fn rebalance_keeps_the_old_replica_when_the_copy_does_not_verify() {
+		let (mkv, stubs, vols) = stub_cluster(&["v0", "v1"]);
+		vols[1].corrupt.store(true, Ordering::SeqCst);
+
+		assert!(!rebalance(&mkv, &move_to(&vols, &[0], &[1])));
+
+		assert_eq!(mkv.get_record(KEY).rvolumes, vec![vols[0].addr.clone()]);
+		assert!(vols[0].blobs.lock().unwrap().contains_key(&key_to_path(KEY)));
+		assert!(!stubs.log.lock().unwrap().iter().any(|l| l.starts_with("DELETE v0")));
+
+		// The bad copy is not left behind either.
+		assert!(vols[1].blobs.lock().unwrap().is_empty());
+	}
+
+	#[test]
+	fn rebalance_keeps_the_old_shards_when_a_copy_fails() {
+		let (mkv, stubs, vols) = stub_cluster(&["v0", "v1", "v2"]);
+		let shard = |i: usize| format!("{}.{}", key_to_path(KEY), i);
+
+		vols[0].blobs.lock().unwrap().insert(shard(0), b"hello".to_vec());
+		vols[1].blobs.lock().unwrap().insert(shard(1), b"world".to_vec());
+
+		let layout = Layout { codec: Codec { data: 1, parity: 1 }, chunk: 5, size: 5 };
+		let before = String::from(Record { rvolumes: vec![vols[0].addr.clone(), vols[1].addr.clone()], deleted: Deleted::No, hash: None, erasure: Some(layout) });
+		mkv.put_record(KEY, Record::from(before.clone())).unwrap();
+
+		vols[2].fail_puts.store(true, Ordering::SeqCst);
+		assert!(!rebalance(&mkv, &move_to(&vols, &[0, 1], &[0, 2])));
+
+		assert_eq!(String::from(mkv.get_record(KEY)), before);
+		assert!(vols[1].blobs.lock().unwrap().contains_key(&shard(1)));
+		assert!(!stubs.log.lock().unwrap().iter().any(|l| l.starts_with("DELETE")));
+	}
+
+	#[test]
+	fn scrub_rewrites_corrupt_parity() {
+		let (mut mkv, _stubs, vols) = stub_cluster(&["v0", "v1", "v2"]);
+		mkv.scrub_repair = true;
+
+		let urls = vols.iter().enumerate().map(|(i, v)| erasure::shard_url(&v.addr, KEY, i)).collect::<Vec<String>>();
+		let data = b"hello erasure coded world".to_vec();
+		let (_, layout) = erasure::put_shards(&urls, Codec { data: 2, parity: 1 }, Some(data.len() as u64), &mut &data[..]);
+
+		let mut hasher = Algorithm::Md5.hasher();
+		hasher.update(&data);
+		let rec = Record { rvolumes: vols.iter().map(|v| v.addr.clone()).collect(), deleted: Deleted::No, hash: Some(hasher.finish()), erasure: Some(layout) };
+		mkv.put_record(KEY, Record::from(String::from(rec))).unwrap();
+
+		let parity = format!("{}.2", key_to_path(KEY));
+		let good = vols[2].blobs.lock().unwrap()[&parity].clone();
+		vols[2].blobs.lock().unwrap().get_mut(&parity).unwrap()[0] ^= 1;
+
+		let mut report = Report::default();
+		scrub(&mkv, KEY, &mkv.get_record(KEY), &mut Budget::new(0), &mut report);
+
+		assert_eq!((report.missing, report.corrupt, report.repaired), (0, 1, 1));
+		assert_eq!(vols[2].blobs.lock().unwrap()[&parity], good);
+	}
+
+	// Serves `mkv` on a free port with `threads` workers, returns its address.
+	fn serve_on(mkv: &Minikeyvalue, threads: usize) -> String {
+		let server = Arc::new(Server::http("127.0.0.1:0").unwrap());
+		let addr = server.server_addr().to_ip().unwrap().to_string();
+
+		for _i in 0..threads {
+			let (mkv, server) = (mkv.clone(), server.clone());
+
+			thread::spawn(move || {
+				while let Ok(req) = server.recv() {
+					mkv.serve(req);
+				}
+			});
+		}
+
+		addr
+	}
+
+	fn put(addr: &str, key: &str, body: &'static str) -> u16 {
+		reqwest::blocking::Client::new().put(&format!("http://{}{}", addr, key)).body(body).send().unwrap().status().as_u16()
+	}
+
+	#[test]
+	fn ranges() {
+		assert_eq!(parse_range("bytes=0-9", 100), Ok(Some((0, 10))));
+		assert_eq!(parse_range("bytes=90-200", 100), Ok(Some((90, 100))));
+
+		// Open ended and suffix.
+		assert_eq!(parse_range("bytes=10-", 100), Ok(Some((10, 100))));
+		assert_eq!(parse_range("bytes=-10", 100), Ok(Some((90, 100))));
+		assert_eq!(parse_range("bytes=-200", 100), Ok(Some((0, 100))));
+
+		// Nothing of the blob in them.
+		assert_eq!(parse_range("bytes=100-", 100), Err(()));
+		assert_eq!(parse_range("bytes=-0", 100), Err(()));
+		assert_eq!(parse_range("bytes=9-0", 100), Err(()));
+
+		// An empty blob has no range to give.
+		assert_eq!(parse_range("bytes=-10", 0), Err(()));
+		assert_eq!(parse_range("bytes=0-", 0), Err(()));
+
+		// The whole blob for what is not served or not understood.
+		assert_eq!(parse_range("bytes=0-1,5-6", 100), Ok(None));
+		assert_eq!(parse_range("items=0-1", 100), Ok(None));
+		assert_eq!(parse_range("bytes=a-1", 100), Ok(None));
+		assert_eq!(parse_range("bytes=5", 100), Ok(None));
+	}
+
+	#[test]
+	fn ranges_of_erasure_coded_keys() {
+		let stubs = Arc::new(Stubs::default());
+		let vols = ["v0", "v1", "v2"].iter().map(|name| stubs.volume(name)).collect::<Vec<_>>();
+
+		let mut mkv = test_mkv(&vols.iter().map(|v| v.addr.as_str()).collect::<Vec<&str>>(), 1);
+		mkv.erasure = Some(Codec { data: 2, parity: 1 });
+		let addr = serve_on(&mkv, 1);
+
+		assert_eq!(put(&addr, KEY, "hello erasure coded world"), 201);
+		assert_eq!(put(&addr, "/empty", ""), 201);
+
+		let get = |key: &str, range: &str| {
+			let resp = reqwest::blocking::Client::new().get(&format!("http://{}{}", addr, key)).header("Range", range).send().unwrap();
+			let content_range = resp.headers().get("Content-Range").map(|v| v.to_str().unwrap().to_string());
+			(resp.status().as_u16(), content_range, resp.text().unwrap())
+		};
+
+		assert_eq!(get(KEY, "bytes=6-12"), (206, Some("bytes 6-12/25".to_string()), "erasure".to_string()));
+		assert_eq!(get(KEY, "bytes=-5"), (206, Some("bytes 20-24/25".to_string()), "world".to_string()));
+		assert_eq!(get(KEY, "bytes=25-"), (416, Some("bytes */25".to_string()), String::new()));
+		assert_eq!(get("/empty", "bytes=-5"), (416, Some("bytes */0".to_string()), String::new()));
+	}
+
+	#[test]
+	fn deleted_keys_are_not_migrated_back() {
+		let stubs = Arc::new(Stubs::default());
+		let (vol, old) = (stubs.volume("v0"), stubs.volume("old"));
+		old.blobs.lock().unwrap().insert(KEY.to_string(), b"hello world".to_vec());
+
+		let mut mkv = test_mkv(&[&vol.addr], 1);
+		mkv.fallback = old.addr.clone();
+		mkv.migrate = true;
+		let addr = serve_on(&mkv, 1);
+
+		assert!(migrate(&mkv, KEY));
+		assert_eq!(vol.blobs.lock().unwrap()[&key_to_path(KEY)], b"hello world");
+
+		let client = reqwest::blocking::Client::builder().redirect(reqwest::redirect::Policy::none()).build().unwrap();
+		let url = format!("http://{}{}", addr, KEY);
+		assert_eq!(client.delete(&url).send().unwrap().status().as_u16(), 204);
+		assert!(vol.blobs.lock().unwrap().is_empty());
+
+		// Still sent on to the fallback, which has it yet, and queued to migrate.
+		let resp = client.get(&url).send().unwrap();
+		assert_eq!(resp.status().as_u16(), 302);
+		assert_eq!(resp.headers()["Location"], &format!("http://{}{}", old.addr, KEY)[..]);
+
+		assert!(migrate(&mkv, &mkv.migrations.pop()));
+		assert!(mkv.get_record(KEY).is_tombstone());
+		assert!(vol.blobs.lock().unwrap().is_empty());
+
+		// Nor does it come back as unlinked, or deleted twice.
+		assert!(mkv.list("", "", 0, true).unwrap().keys.is_empty());
+		assert_eq!(client.delete(&url).send().unwrap().status().as_u16(), 404);
+	}
+
+	#[test]
+	fn second_put_is_refused_while_the_first_is_in_flight() {
+		let (mkv, stubs, vols) = stub_cluster(&["v0"]);
+		let addr = serve_on(&mkv, 2);
+
+		let (release, hold) = channel::unbounded::<()>();
+		*vols[0].hold.lock().unwrap() = Some(hold);
+
+		let first = {
+			let addr = addr.clone();
+			thread::spawn(move || put(&addr, "/b", "first"))
+		};
+
+		// Wait for the first PUT to reach the volume, with the key locked.
+		for _i in 0..500 {
+			if stubs.log.lock().unwrap().iter().any(|l| l.starts_with("PUT v0")) { break; }
+			thread::sleep(Duration::from_millis(10));
+		}
+
+		assert_eq!(put(&addr, "/b", "second"), 409);
+
+		drop(release);
+		assert_eq!(first.join().unwrap(), 201);
+
+		// Only the first one ever got to the volume.
+		assert_eq!(stubs.log.lock().unwrap().iter().filter(|l| l.starts_with("PUT")).count(), 1);
+		assert_eq!(vols[0].blobs.lock().unwrap()[&key_to_path("/b")], b"first");
+	}
+}
@@ -0,0 +1,183 @@
+use std::io;
+use std::fs;
+use std::thread;
+use std::path::PathBuf;
+use std::sync::Mutex;
+use std::time::{Duration, Instant};
+use std::collections::BTreeMap;
+use std::sync::atomic::{AtomicBool, AtomicU64, Ordering};
+
+// How often a long job prints where it is and saves its checkpoint.
+const REPORT_INTERVAL: Duration = Duration::from_secs(5);
+
+// Counters of a rebuild or rebalance, bumped by the workers and printed by
+// `report` while the job runs.
+pub struct Progress {
+	job: &'static str,
+	unit: &'static str,
+	change: &'static str,
+	start: Instant,
+	finished: AtomicBool,
+	pub total: AtomicU64,
+	pub scanned: AtomicU64,
+	pub changed: AtomicU64,
+	pub failed: AtomicU64,
+}
+
+impl Progress {
+	// Printed as `<job>: <scanned>/<total> <unit> scanned, <changed> <change>, ...`.
+	pub fn new(job: &'static str, unit: &'static str, change: &'static str) -> Self {
+		Self {
+			job,
+			unit,
+			change,
+			start: Instant::now(),
+			finished: AtomicBool::new(false),
+			total: AtomicU64::new(0),
+			scanned: AtomicU64::new(0),
+			changed: AtomicU64::new(0),
+			failed: AtomicU64::new(0),
+		}
+	}
+
+	// Prints the progress and saves `checkpoint` every `REPORT_INTERVAL`
+	// until `finish` is called, then prints a last time.
+	pub fn report(&self, checkpoint: &Checkpoint) {
+		let mut last = Instant::now();
+
+		while !self.finished.load(Ordering::SeqCst) {
+			thread::sleep(Duration::from_millis(100));
+
+			if last.elapsed() >= REPORT_INTERVAL {
+				println!("{}", self.line());
+
+				if let Err(e) = checkpoint.save() {
+					eprintln!("{}: cannot save checkpoint: {}", self.job, e);
+				}
+
+				last = Instant::now();
+			}
+		}
+
+		println!("{}", self.line());
+	}
+
+	pub fn finish(&self) {
+		self.finished.store(true, Ordering::SeqCst);
+	}
+
+	fn line(&self) -> String {
+		let total = self.total.load(Ordering::SeqCst);
+		let scanned = self.scanned.load(Ordering::SeqCst);
+		let elapsed = self.start.elapsed().as_secs_f64();
+		let rate = if elapsed > 0.0 { scanned as f64 / elapsed } else { 0.0 };
+
+		let eta = if self.finished.load(Ordering::SeqCst) {
+			"done".to_string()
+		} else if rate > 0.0 && total >= scanned {
+			format!("ETA {}s", ((total - scanned) as f64 / rate).ceil())
+		} else {
+			"ETA unknown".to_string()
+		};
+
+		format!("{}: {}/{} {} scanned, {} {}, {} failed, {:.1}/s, {}",
+			self.job, scanned, total, self.unit,
+			self.changed.load(Ordering::SeqCst), self.change, self.failed.load(Ordering::SeqCst), rate, eta)
+	}
+}
+
+// Where a job can pick up again after it was interrupted. Work is handed out
+// in order and numbered, but finishes in any order; the checkpoint is the
+// last piece of work before which everything is done.
+//
+// The file holds the job on the first line and the label of that piece of
+// work, a key or a directory, after it.
+pub struct Checkpoint {
+	path: Option<PathBuf>,
+	job: &'static str,
+	state: Mutex<State>,
+}
+
+#[derive(Default)]
+struct State {
+	next: u64,
+	low: u64,
+	done: BTreeMap<u64, String>,
+	mark: Option<String>,
+}
+
+impl Checkpoint {
+	pub fn new(path: Option<PathBuf>, job: &'static str) -> Self {
+		Self { path, job, state: Mutex::new(State::default()) }
+	}
+
+	// Label saved by an earlier run of the same job, if there is one.
+	pub fn load(&self) -> io::Result<Option<String>> {
+		let path = match &self.path {
+			Some(path) => path,
+			None => return Ok(None),
+		};
+
+		let contents = match fs::read_to_string(path) {
+			Ok(contents) => contents,
+			Err(ref e) if e.kind() == io::ErrorKind::NotFound => return Ok(None),
+			Err(e) => return Err(e),
+		};
+
+		match contents.split_once('\n') {
+			Some((job, label)) if job == self.job => Ok(Some(label.to_string())),
+			_ => Err(io::Error::new(io::ErrorKind::InvalidData, format!("{} is not a {} checkpoint", path.display(), self.job))),
+		}
+	}
+
+	// Numbers the next piece of work handed out.
+	pub fn start(&self) -> u64 {
+		let mut state = self.state.lock().unwrap();
+		state.next += 1;
+		state.next - 1
+	}
+
+	pub fn done(&self, seq: u64, label: &str) {
+		let mut state = self.state.lock().unwrap();
+		state.done.insert(seq, label.to_string());
+
+		loop {
+			let low = state.low;
+
+			match state.done.remove(&low) {
+				Some(label) => {
+					state.mark = Some(label);
+					state.low += 1;
+				},
+				None => break,
+			}
+		}
+	}
+
+	pub fn save(&self) -> io::Result<()> {
+		let path = match &self.path {
+			Some(path) => path,
+			None => return Ok(()),
+		};
+
+		let mark = match self.state.lock().unwrap().mark.clone() {
+			Some(mark) => mark,
+			None => return Ok(()),
+		};
+
+		let tmp = path.with_extension("tmp");
+		fs::write(&tmp, format!("{}\n{}", self.job, mark))?;
+		fs::rename(&tmp, path)
+	}
+
+	// The job went through, nothing to resume.
+	pub fn clear(&self) -> io::Result<()> {
+		match &self.path {
+			Some(path) => match fs::remove_file(path) {
+				Err(ref e) if e.kind() == io::ErrorKind::NotFound => Ok(()),
+				r => r,
+			},
+			None => Ok(()),
+		}
+	}
+}
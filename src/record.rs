@@ -1,5 +1,8 @@
 use std::convert::From;
 
+use crate::digest::Digest;
+use crate::erasure::Layout;
+
 #[derive(Debug, PartialEq)]
 pub enum Deleted {
 	No,
@@ -11,7 +14,8 @@ pub enum Deleted {
 pub struct Record {
 	pub rvolumes: Vec<String>,
 	pub deleted: Deleted, // TODO: handle pub later
-	pub hash: String, // TODO: handle pub later
+	pub hash: Option<Digest>, // TODO: handle pub later
+	pub erasure: Option<Layout>, // shards instead of replicas when set
 }
 
 impl Record {
@@ -19,23 +23,43 @@ impl Record {
 		Self {
 			rvolumes: vec![],
 			deleted: Deleted::Hard,
-			hash: String::new(),
+			hash: None,
+			erasure: None,
 		}
 	}
+
+	// What a DELETE leaves behind while there is a fallback cluster, so the
+	// key is not migrated back from it: deleted, and on no volumes.
+	pub fn tombstone() -> Self {
+		Self { deleted: Deleted::Soft, ..Record::new() }
+	}
+
+	pub fn is_tombstone(&self) -> bool {
+		self.deleted == Deleted::Soft && self.rvolumes.iter().all(String::is_empty)
+	}
 }
 
 impl From<String> for Record {
 	fn from(mut string: String) -> Self {
 		let mut rec = Record::new();
+		rec.deleted = Deleted::No;
 
 		if string.starts_with("DELETED") {
 			rec.deleted = Deleted::Soft;
 			string = string[7..].to_string();
 		}
 
+		// Either the original `HASH<md5 hex>` or `HASH<algorithm>:<hex>`.
 		if string.starts_with("HASH") {
-			rec.hash = string[4..36].to_string();
-			string = string[36..].to_string();
+			if let Some((digest, len)) = Digest::decode(&string[4..]) {
+				rec.hash = Some(digest);
+				string = string[4 + len..].to_string();
+			}
+		}
+
+		if let Some((layout, len)) = Layout::decode(&string) {
+			rec.erasure = Some(layout);
+			string = string[len..].to_string();
 		}
 
 		rec.rvolumes = string.split(',').map(|x| x.to_string()).collect();
@@ -44,21 +68,99 @@ impl From<String> for Record {
 	}
 }
 
-impl Into<String> for Record {
-	fn into(self) -> String {
+impl From<Record> for String {
+	fn from(rec: Record) -> Self {
 		let mut cc = String::new();
 
-		if self.deleted == Deleted::Hard { panic!("Cannot put HARD delete in the database"); }
+		if rec.deleted == Deleted::Hard { panic!("Cannot put HARD delete in the database"); }
 
-		if self.deleted == Deleted::Soft { cc.push_str("DELETED"); }
+		if rec.deleted == Deleted::Soft { cc.push_str("DELETED"); }
 
-		if self.hash.len() == 32 {
+		if let Some(digest) = rec.hash {
 			cc.push_str("HASH");
-			cc.push_str(&self.hash);
+			cc.push_str(&digest.encode());
 		}
 
-		cc.push_str(&self.rvolumes.join(","));
+		if let Some(layout) = rec.erasure {
+			cc.push_str(&layout.encode());
+		}
+
+		cc.push_str(&rec.rvolumes.join(","));
 
 		cc
 	}
-}
\ No newline at end of file
+}
+#[cfg(test)]
+mod tests {
+	use super::*;
+	use crate::digest::Algorithm;
+	use crate::erasure::Codec;
+
+	// Reads `s` and checks that it is written back the same.
+	fn round_trip(s: &str) -> Record {
+		assert_eq!(String::from(Record::from(s.to_string())), s);
+		Record::from(s.to_string())
+	}
+
+	#[test]
+	fn original_records_still_read() {
+		let rec = round_trip("HASH5d41402abc4b2a76b9719d911017c592localhost:3001/sv03,localhost:3002/sv05");
+		assert_eq!(rec.deleted, Deleted::No);
+		assert_eq!(rec.hash.as_ref().map(|d| (d.algorithm, d.hex())), Some((Algorithm::Md5, "5d41402abc4b2a76b9719d911017c592".to_string())));
+		assert_eq!(rec.erasure, None);
+		assert_eq!(rec.rvolumes, vec!["localhost:3001/sv03", "localhost:3002/sv05"]);
+
+		let rec = round_trip("DELETEDHASH5d41402abc4b2a76b9719d911017c592localhost:3001");
+		assert_eq!(rec.deleted, Deleted::Soft);
+		assert_eq!(rec.rvolumes, vec!["localhost:3001"]);
+
+		let rec = round_trip("localhost:3001,localhost:3002");
+		assert_eq!(rec.hash, None);
+		assert_eq!(rec.rvolumes, vec!["localhost:3001", "localhost:3002"]);
+	}
+
+	#[test]
+	fn tagged_hashes_round_trip() {
+		let hex = "2cf24dba5fb0a30e26e83b2ac5b9e29e1b161e5c1fa7425e73043362938b9824";
+
+		let rec = round_trip(&format!("HASHsha256:{}localhost:3001", hex));
+		assert_eq!(rec.hash.as_ref().map(|d| (d.algorithm, d.hex())), Some((Algorithm::Sha256, hex.to_string())));
+		assert_eq!(rec.rvolumes, vec!["localhost:3001"]);
+
+		let rec = round_trip(&format!("DELETEDHASHblake3:{}localhost:3001", hex));
+		assert_eq!(rec.deleted, Deleted::Soft);
+		assert_eq!(rec.hash.map(|d| d.algorithm), Some(Algorithm::Blake3));
+	}
+
+	#[test]
+	fn erasure_layouts_round_trip() {
+		let rec = round_trip("DELETEDHASHxxh3:000102030405060708090a0b0c0d0e0fERASURE4,2,16384,100000|a:1,b:1,,d:1,e:1,f:1");
+		assert_eq!(rec.deleted, Deleted::Soft);
+		assert_eq!(rec.hash.map(|d| d.algorithm), Some(Algorithm::Xxh3));
+		assert_eq!(rec.erasure, Some(Layout { codec: Codec { data: 4, parity: 2 }, chunk: 16384, size: 100000 }));
+
+		// A shard that was never written keeps its place.
+		assert_eq!(rec.rvolumes, vec!["a:1", "b:1", "", "d:1", "e:1", "f:1"]);
+
+		// Layouts kept next to shards are records without volumes.
+		let rec = round_trip("HASH5d41402abc4b2a76b9719d911017c592ERASURE2,1,5,10|");
+		assert!(rec.erasure.is_some());
+		assert_eq!(rec.rvolumes, vec![""]);
+	}
+
+	#[test]
+	fn tombstones_round_trip() {
+		assert_eq!(String::from(Record::tombstone()), "DELETED");
+		assert!(round_trip("DELETED").is_tombstone());
+
+		// Unlinked keys still have their volumes.
+		assert!(!round_trip("DELETEDlocalhost:3001").is_tombstone());
+		assert!(!round_trip("").is_tombstone());
+	}
+
+	#[test]
+	#[should_panic]
+	fn hard_deletes_are_not_stored() {
+		let _ = String::from(Record::new());
+	}
+}
@@ -1,8 +1,21 @@
 use std::fmt;
 use std::error;
+use std::io::{self, Read};
+use std::sync::{Arc, OnceLock};
 
+use crossbeam::channel::{self, Receiver};
 use reqwest::StatusCode;
-use reqwest::blocking::{Client, Body};
+use reqwest::header::RANGE;
+use reqwest::blocking::{Client, Body, Response};
+
+// Size of the pieces a body is split into while it is streamed to volumes.
+const CHUNK_SIZE: usize = 64 * 1024;
+
+// Chunks queued for a single volume before the reader has to wait on it.
+const CHUNKS_IN_FLIGHT: usize = 16;
+
+// Outcome of an upload, sent back from the thread that ran it.
+pub type PutResult = Result<(), Box<dyn error::Error + Send + Sync>>;
 
 #[derive(Debug)]
 pub enum Error { WrongStatusCode }
@@ -20,38 +33,310 @@ impl error::Error for Error {
 }
 
 pub fn remote_delete(remote: String) -> Result<(), Box<dyn error::Error>> {
-	let resp = Client::new().delete(&remote).body(Body::from("")).send()?;
+	let resp = client()?.delete(&remote).body(Body::from("")).send()?;
 
 	if resp.status() != StatusCode::NO_CONTENT { // 204
 		return Err(Box::new(Error::WrongStatusCode));
 	}
 
-	return Ok(());
+	Ok(())
 }
 
-pub fn remote_put(remote: &String, _length: usize, body: &String) -> Result<(), Box<dyn std::error::Error>> {		
-	let resp = Client::new().put(remote).body::<String>(body.into()).send()?;
+// Streams `body` to `remote`. Without a `length` the body is sent chunked.
+pub fn remote_put<R: Read + Send + 'static>(remote: &str, length: Option<u64>, body: R) -> PutResult {
+	let body = match length {
+		Some(length) => Body::sized(body, length),
+		None => Body::new(body),
+	};
+
+	let resp = streaming_client()?.put(remote).body(body).send()?;
 
 	if resp.status() != StatusCode::CREATED && resp.status() != StatusCode::NO_CONTENT { // 201 && 204
 		return Err(Box::new(Error::WrongStatusCode));
 	}
-	return Ok(());
+	Ok(())
+}
+
+// Streams `body` to every remote at once. The body is read only once and at
+// most `CHUNKS_IN_FLIGHT` chunks are buffered per remote, so memory stays
+// bounded whatever the size of the body; the upload runs at the pace of the
+// slowest remote. A remote that fails is dropped and the others carry on.
+// Results are in the same order as `remotes`.
+pub fn remote_put_all(remotes: &[String], length: Option<u64>, body: &mut dyn Read) -> Vec<PutResult> {
+	remote_put_each(remotes, length, || {
+		let mut chunk = vec![0u8; CHUNK_SIZE];
+
+		let n = loop {
+			match body.read(&mut chunk) {
+				Ok(n) => break n,
+				Err(ref e) if e.kind() == io::ErrorKind::Interrupted => continue,
+				Err(e) => return Err(e),
+			}
+		};
+
+		if n == 0 { return Ok(None); }
+
+		chunk.truncate(n);
+		let chunk = Arc::new(chunk);
+
+		Ok(Some(remotes.iter().map(|_| chunk.clone()).collect()))
+	})
+}
+
+// Like `remote_put_all`, but every remote gets a body of its own: each call
+// to `next` hands out the following chunk for every remote, in the order of
+// `remotes`, or `None` once the bodies are complete.
+pub fn remote_put_each<F>(remotes: &[String], length: Option<u64>, mut next: F) -> Vec<PutResult>
+where
+	F: FnMut() -> io::Result<Option<Vec<Arc<Vec<u8>>>>>,
+{
+	crossbeam::scope(|scope| {
+		let mut senders = Vec::new();
+		let mut handles = Vec::new();
+
+		for remote in remotes {
+			let (tx, rx) = channel::bounded(CHUNKS_IN_FLIGHT);
+			senders.push(Some(tx));
+			handles.push(scope.spawn(move |_| remote_put(remote, length, ChunkReader::new(rx))));
+		}
+
+		let mut complete = true;
+
+		loop {
+			let chunks = match next() {
+				Ok(Some(chunks)) => chunks,
+				Ok(None) => break,
+				Err(e) => {
+					eprintln!("remote_put_all: error while reading body: {}", e);
+					complete = false;
+					break;
+				}
+			};
+
+			for (tx, chunk) in senders.iter_mut().zip(chunks) {
+				if let Some(sender) = tx {
+					// The receiving end only goes away when that upload failed.
+					if sender.send(chunk).is_err() { *tx = None; }
+				}
+			}
+
+			if senders.iter().all(Option::is_none) { break; }
+		}
+
+		// An empty chunk marks the end of the body, hanging up without one makes
+		// the uploads fail instead of storing a truncated blob.
+		if complete {
+			for sender in senders.iter().flatten() {
+				let _ = sender.send(Arc::new(Vec::new()));
+			}
+		}
+
+		drop(senders);
+
+		handles.into_iter()
+			.map(|h| h.join().unwrap_or_else(|_| Err("remote_put_all: upload thread panicked".into())))
+			.collect()
+	}).expect("remote_put_all: crossbeam failed")
 }
 
-pub fn remote_get(remote: &String) -> Result<String, Box<dyn std::error::Error>> {
-	let mut resp = Client::new().get(remote).body(Body::from("")).send()?;
+// Opens `remote` for reading. The response streams the body and carries its
+// length if the volume sent one.
+pub fn remote_open(remote: &str) -> Result<Response, Box<dyn error::Error>> {
+	let resp = streaming_client()?.get(remote).body(Body::from("")).send()?;
 
 	if resp.status() != StatusCode::OK {
 		return Err(Box::new(Error::WrongStatusCode));
 	}
 
+	Ok(resp)
+}
+
+// Like `remote_open`, but only bytes `start` up to `end` of the blob.
+pub fn remote_open_range(remote: &str, start: u64, end: u64) -> Result<Response, Box<dyn error::Error>> {
+	let range = format!("bytes={}-{}", start, end - 1);
+	let resp = streaming_client()?.get(remote).header(RANGE, range).body(Body::from("")).send()?;
+
+	if resp.status() != StatusCode::PARTIAL_CONTENT {
+		return Err(Box::new(Error::WrongStatusCode));
+	}
+
+	Ok(resp)
+}
+
+pub fn remote_get(remote: &str) -> Result<Vec<u8>, Box<dyn error::Error>> {
+	let mut resp = remote_open(remote)?;
+
 	let mut buffer = Vec::<u8>::new();
 	resp.copy_to(&mut buffer)?;
 
-	return Ok(String::from_utf8(buffer)?);
+	Ok(buffer)
 }
 
+// A volume that can't be reached doesn't have the blob either.
 pub fn remote_head(remote: &String) -> bool {
-	let resp = Client::new().head(remote).body(Body::from("")).send().expect("remote_head: error while sending request");
-	return resp.status() == 200;
-}
\ No newline at end of file
+	match client().and_then(|c| c.head(remote).body(Body::from("")).send()) {
+		Ok(resp) => resp.status() == 200,
+		Err(_e) => false,
+	}
+}
+
+// Size of the blob at `remote`, `None` if it isn't there.
+pub fn remote_size(remote: &str) -> Option<u64> {
+	let resp = client().and_then(|c| c.head(remote).body(Body::from("")).send()).ok()?;
+
+	if resp.status() != StatusCode::OK { return None; }
+
+	resp.headers().get(reqwest::header::CONTENT_LENGTH)?.to_str().ok()?.parse().ok()
+}
+
+// Reads a blob, or bytes `start` up to `end` of it, from the first of
+// `remotes` that has it. If that one fails halfway, the rest is read from the
+// next one that can serve it.
+pub struct FailoverReader {
+	remotes: Vec<String>,
+	next: usize,
+	resp: Response,
+	pos: u64,
+	end: u64,
+	degraded: bool,
+}
+
+impl FailoverReader {
+	pub fn open(remotes: &[String], range: Option<(u64, u64)>) -> io::Result<Self> {
+		let mut degraded = false;
+
+		for (i, remote) in remotes.iter().enumerate() {
+			let resp = match range {
+				Some((start, end)) => remote_open_range(remote, start, end),
+				None => remote_open(remote),
+			};
+
+			match resp {
+				Ok(resp) => {
+					let start = range.map(|(start, _)| start).unwrap_or(0);
+					let length = resp.content_length().filter(|&l| range.map(|(s, e)| l == e - s).unwrap_or(true));
+
+					if let Some(length) = length {
+						return Ok(Self { remotes: remotes.to_vec(), next: i + 1, resp, pos: start, end: start + length, degraded });
+					}
+
+					eprintln!("{} did not send the length asked for", remote);
+				},
+				Err(e) => eprintln!("cannot open {}: {}", remote, e),
+			}
+
+			degraded = true;
+		}
+
+		Err(io::Error::new(io::ErrorKind::NotFound, "no replica left to read"))
+	}
+
+	// Bytes left to read.
+	pub fn remaining(&self) -> u64 {
+		self.end - self.pos
+	}
+
+	// True if a replica turned out to be missing or broken.
+	pub fn degraded(&self) -> bool {
+		self.degraded
+	}
+
+	// Carries on at `pos` with the next replica that can serve the rest.
+	fn failover(&mut self) -> bool {
+		self.degraded = true;
+
+		while self.next < self.remotes.len() {
+			let remote = &self.remotes[self.next];
+			self.next += 1;
+
+			match remote_open_range(remote, self.pos, self.end) {
+				Ok(resp) if resp.content_length() == Some(self.end - self.pos) => {
+					self.resp = resp;
+					return true;
+				},
+				Ok(_) => eprintln!("{} did not send the length asked for", remote),
+				Err(e) => eprintln!("cannot resume from {}: {}", remote, e),
+			}
+		}
+
+		false
+	}
+}
+
+impl Read for FailoverReader {
+	fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+		let max = buf.len().min((self.end - self.pos) as usize);
+		if max == 0 { return Ok(0); }
+
+		loop {
+			let err = match self.resp.read(&mut buf[..max]) {
+				Ok(0) => io::Error::new(io::ErrorKind::UnexpectedEof, "replica ended early"),
+				Ok(n) => {
+					self.pos += n as u64;
+					return Ok(n);
+				},
+				Err(ref e) if e.kind() == io::ErrorKind::Interrupted => continue,
+				Err(e) => e,
+			};
+
+			eprintln!("read of {} failed at byte {}: {}", self.remotes[self.next - 1], self.pos, err);
+			if !self.failover() { return Err(err); }
+		}
+	}
+}
+
+// Clients are shared, setting one up costs far more than a request to a
+// volume and they keep connections around for reuse.
+static CLIENT: OnceLock<Client> = OnceLock::new();
+static STREAMING_CLIENT: OnceLock<Client> = OnceLock::new();
+
+fn client() -> reqwest::Result<Client> {
+	shared(&CLIENT, Client::builder())
+}
+
+// Blobs can take far longer to move than the default 30 second timeout allows.
+fn streaming_client() -> reqwest::Result<Client> {
+	shared(&STREAMING_CLIENT, Client::builder().timeout(None))
+}
+
+fn shared(cell: &OnceLock<Client>, builder: reqwest::blocking::ClientBuilder) -> reqwest::Result<Client> {
+	if let Some(client) = cell.get() { return Ok(client.clone()); }
+
+	let client = builder.build()?;
+	Ok(cell.get_or_init(|| client).clone())
+}
+
+// Read side of one upload in `remote_put_all`.
+struct ChunkReader {
+	rx: Receiver<Arc<Vec<u8>>>,
+	chunk: Arc<Vec<u8>>,
+	pos: usize,
+	done: bool,
+}
+
+impl ChunkReader {
+	fn new(rx: Receiver<Arc<Vec<u8>>>) -> Self {
+		Self { rx, chunk: Arc::new(Vec::new()), pos: 0, done: false }
+	}
+}
+
+impl Read for ChunkReader {
+	fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+		while !self.done && self.pos == self.chunk.len() {
+			match self.rx.recv() {
+				Ok(chunk) if chunk.is_empty() => self.done = true,
+				Ok(chunk) => {
+					self.chunk = chunk;
+					self.pos = 0;
+				},
+				Err(_e) => return Err(io::Error::new(io::ErrorKind::UnexpectedEof, "body was not read to the end")),
+			}
+		}
+
+		let n = buf.len().min(self.chunk.len() - self.pos);
+		buf[..n].copy_from_slice(&self.chunk[self.pos..self.pos + n]);
+		self.pos += n;
+
+		Ok(n)
+	}
+}
@@ -0,0 +1,255 @@
+use std::fmt;
+use std::io::{self, Read};
+use std::thread;
+use std::time::{Duration, Instant};
+
+use crate::digest::{Digest, DigestReader};
+use crate::erasure::{self, Layout, Stripes};
+use crate::remote::{remote_head, remote_open};
+
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum Status {
+	Good,
+	Missing,
+	Corrupt,
+}
+
+// Bytes per second the scrubber may read from volumes, shared by everything
+// it reads so the whole pass stays under it. A rate of 0 is no limit.
+pub struct Budget {
+	rate: u64,
+	start: Instant,
+	spent: u64,
+}
+
+impl Budget {
+	pub fn new(rate: u64) -> Self {
+		Self { rate, start: Instant::now(), spent: 0 }
+	}
+
+	// Counts `n` more bytes, sleeping for as long as they put us ahead.
+	fn spend(&mut self, n: usize) {
+		if self.rate == 0 { return; }
+
+		self.spent += n as u64;
+
+		let due = Duration::from_secs_f64(self.spent as f64 / self.rate as f64);
+		let elapsed = self.start.elapsed();
+
+		if due > elapsed {
+			thread::sleep(due - elapsed);
+		}
+	}
+}
+
+struct Throttled<'a, R: Read> {
+	inner: R,
+	budget: &'a mut Budget,
+}
+
+impl<'a, R: Read> Read for Throttled<'a, R> {
+	fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+		let n = self.inner.read(buf)?;
+		self.budget.spend(n);
+		Ok(n)
+	}
+}
+
+// Tally of a scrub pass.
+#[derive(Default)]
+pub struct Report {
+	pub keys: u64,
+	pub missing: u64,
+	pub corrupt: u64,
+	pub repaired: u64,
+}
+
+impl fmt::Display for Report {
+	fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+		write!(f, "{} keys, {} missing, {} corrupt, {} repaired", self.keys, self.missing, self.corrupt, self.repaired)
+	}
+}
+
+// Reads the replica at `url` through and checks it against `expected`.
+// Without a digest to compare with, only whether it is there is checked.
+pub fn check(url: &str, expected: Option<&Digest>, budget: &mut Budget) -> Status {
+	let expected = match expected {
+		Some(d) => d,
+		None => return if remote_head(&url.to_string()) { Status::Good } else { Status::Missing },
+	};
+
+	match remote_open(url) {
+		Ok(resp) => verify(Throttled { inner: resp, budget }, expected),
+		Err(_e) => Status::Missing,
+	}
+}
+
+// Checks every shard of an erasure coded blob, parity included. Each stripe
+// is decoded from `data` of the shards and encoded again, and a shard that
+// holds anything else is corrupt. If the decoded blob is not `expected`, one
+// of the shards it was decoded from is bad itself, so it is checked again
+// leaving each of those out in turn. When no set of shards decodes to
+// `expected` all of them are taken for corrupt.
+// Without a digest to compare with, only whether they are there is checked.
+pub fn check_each_shard(urls: &[String], layout: Layout, expected: Option<&Digest>, budget: &mut Budget) -> Vec<Status> {
+	let expected = match expected {
+		Some(d) => d,
+		None => return urls.iter()
+			.map(|u| if !u.is_empty() && remote_head(u) { Status::Good } else { Status::Missing })
+			.collect(),
+	};
+
+	let (first, used) = match compare_shards(urls, layout, expected, None, budget) {
+		Pass::Matched(statuses) | Pass::Undecodable(statuses) => return statuses,
+		Pass::Mismatched(statuses, used) => (statuses, used),
+	};
+
+	for left_out in used {
+		if let Pass::Matched(statuses) = compare_shards(urls, layout, expected, Some(left_out), budget) {
+			return statuses;
+		}
+	}
+
+	first.into_iter()
+		.map(|s| if s == Status::Missing { s } else { Status::Corrupt })
+		.collect()
+}
+
+enum Pass {
+	Matched(Vec<Status>),
+	// With the shards it was decoded from.
+	Mismatched(Vec<Status>, Vec<usize>),
+	// Fewer than `data` shards to decode from.
+	Undecodable(Vec<Status>),
+}
+
+// One read through every shard, decoding from the first `data` of them
+// other than `left_out`.
+fn compare_shards(urls: &[String], layout: Layout, expected: &Digest, left_out: Option<usize>, budget: &mut Budget) -> Pass {
+	let mut stripes = Stripes::open(urls, layout);
+	let mut statuses = stripes.present().into_iter()
+		.map(|p| if p { Status::Good } else { Status::Missing })
+		.collect::<Vec<Status>>();
+
+	let data = layout.codec.data;
+	let mut hasher = expected.algorithm.hasher();
+	let mut used = Vec::new();
+
+	while let Some((pieces, n)) = stripes.next_stripe() {
+		budget.spend(pieces.iter().flatten().map(Vec::len).sum());
+
+		// Ending early is as bad as holding the wrong bytes.
+		for (status, piece) in statuses.iter_mut().zip(pieces.iter()) {
+			if piece.is_none() && *status == Status::Good { *status = Status::Corrupt; }
+		}
+
+		let from = (0..pieces.len())
+			.filter(|&i| pieces[i].is_some() && Some(i) != left_out)
+			.take(data)
+			.collect::<Vec<usize>>();
+
+		if from.len() < data { return Pass::Undecodable(statuses); }
+
+		let expected_pieces = match erasure::reencode(layout.codec, &pieces, &from) {
+			Ok(p) => p,
+			Err(_e) => return Pass::Undecodable(statuses),
+		};
+
+		for (i, piece) in pieces.iter().enumerate() {
+			if piece.as_ref().map(|p| *p != expected_pieces[i]).unwrap_or(false) {
+				statuses[i] = Status::Corrupt;
+			}
+		}
+
+		let stripe = expected_pieces[..data].concat();
+		hasher.update(&stripe[..n]);
+
+		for i in from {
+			if !used.contains(&i) { used.push(i); }
+		}
+	}
+
+	if hasher.finish() == *expected { Pass::Matched(statuses) } else { Pass::Mismatched(statuses, used) }
+}
+
+fn verify<R: Read>(body: R, expected: &Digest) -> Status {
+	let mut reader = DigestReader::new(body, &[expected.algorithm]);
+
+	// A blob that can't be read to the end is as good as gone.
+	if io::copy(&mut reader, &mut io::sink()).is_err() { return Status::Missing; }
+
+	if reader.finish()[0] == *expected { Status::Good } else { Status::Corrupt }
+}
+
+#[cfg(test)]
+mod tests {
+	use super::*;
+	use std::sync::Arc;
+	use crate::digest::Algorithm;
+	use crate::erasure::{put_shards, repair_shards, shard_url, Codec};
+	use crate::hash::key_to_path;
+	use crate::stub::Stubs;
+
+	use Status::*;
+
+	const CODEC: Codec = Codec { data: 4, parity: 2 };
+
+	#[test]
+	fn every_shard_is_checked() {
+		let stubs = Arc::new(Stubs::default());
+		let vol = stubs.volume("v0");
+		let urls = (0..CODEC.shards()).map(|i| shard_url(&vol.addr, "/a", i)).collect::<Vec<String>>();
+
+		// A few stripes, the last one short.
+		let data = (0..600000).map(|i| (i * 7 + i / 251) as u8).collect::<Vec<u8>>();
+		let (_, layout) = put_shards(&urls, CODEC, Some(data.len() as u64), &mut &data[..]);
+		let before = vol.blobs.lock().unwrap().clone();
+
+		let mut hasher = Algorithm::Md5.hasher();
+		hasher.update(&data);
+		let digest = hasher.finish();
+
+		let check = || check_each_shard(&urls, layout, Some(&digest), &mut Budget::new(0));
+		let path = |i: usize| urls[i][urls[i].find(&key_to_path("/a")).unwrap()..].to_string();
+		let flip = |i: usize, at: usize| vol.blobs.lock().unwrap().get_mut(&path(i)).unwrap()[at] ^= 1;
+		let restore = || *vol.blobs.lock().unwrap() = before.clone();
+
+		assert_eq!(check(), vec![Good; 6]);
+
+		// Parity, which decoding alone never reads.
+		flip(5, 0);
+		assert_eq!(check(), vec![Good, Good, Good, Good, Good, Corrupt]);
+		restore();
+
+		// Data, found by leaving it out, in the second stripe.
+		flip(1, layout.chunk + 3);
+		assert_eq!(check(), vec![Good, Corrupt, Good, Good, Good, Good]);
+
+		let results = repair_shards(&urls, &[1], layout).unwrap();
+		assert!(results.iter().all(Result::is_ok));
+		assert_eq!(*vol.blobs.lock().unwrap(), before);
+
+		// Gone and cut short.
+		vol.blobs.lock().unwrap().remove(&path(2));
+		vol.blobs.lock().unwrap().get_mut(&path(3)).unwrap().truncate(layout.chunk);
+		assert_eq!(check(), vec![Good, Good, Missing, Corrupt, Good, Good]);
+		restore();
+
+		// Gone and corrupt.
+		vol.blobs.lock().unwrap().remove(&path(2));
+		flip(0, 0);
+		assert_eq!(check(), vec![Corrupt, Good, Missing, Good, Good, Good]);
+		restore();
+
+		// Two bad data shards can't be told apart from the good ones.
+		flip(0, 0);
+		flip(1, 0);
+		assert_eq!(check(), vec![Corrupt; 6]);
+		restore();
+
+		// Nothing to compare with but whether they are there.
+		flip(0, 0);
+		vol.blobs.lock().unwrap().remove(&path(4));
+		assert_eq!(check_each_shard(&urls, layout, None, &mut Budget::new(0)), vec![Good, Good, Good, Good, Missing, Good]);
+	}
+}
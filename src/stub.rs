@@ -0,0 +1,200 @@
+use std::io::{self, BufRead, BufReader, Read, Write};
+use std::net::{TcpListener, TcpStream};
+use std::thread;
+use std::sync::{Arc, Mutex, OnceLock};
+use std::sync::atomic::{AtomicBool, AtomicU64, Ordering};
+use std::collections::HashMap;
+
+use crossbeam::channel::Receiver;
+
+use crate::index::IndexStore;
+use crate::record::Record;
+
+// Volumes for tests, served from memory on a free port. Every request made to
+// them is logged as `<METHOD> <volume> [<volumes>]`, the volumes being where
+// the watched record pointed at that moment. A GET may ask for a single
+// `Range` of `bytes=<first>-<last>`.
+//
+// Plain sockets rather than tiny_http: its connection pool can leave a new
+// connection queued behind busy ones, which stalls uploads that go in
+// lockstep, like the shards of one blob to the same volume.
+#[derive(Default)]
+pub struct Stubs {
+	pub log: Mutex<Vec<String>>,
+	names: Mutex<HashMap<String, String>>,
+	watched: OnceLock<(Arc<dyn IndexStore>, String)>,
+}
+
+pub struct StubVolume {
+	pub name: String,
+	pub addr: String,
+	pub blobs: Mutex<HashMap<String, Vec<u8>>>,
+	pub fail_puts: AtomicBool,
+	pub corrupt: AtomicBool,
+	// Bytes of blobs sent back.
+	pub sent: AtomicU64,
+	// PUTs wait on this until it is sent to or dropped.
+	pub hold: Mutex<Option<Receiver<()>>>,
+}
+
+struct StubRequest {
+	method: String,
+	path: String,
+	range: Option<(usize, usize)>,
+	body: Vec<u8>,
+}
+
+impl Stubs {
+	pub fn volume(self: &Arc<Self>, name: &str) -> Arc<StubVolume> {
+		let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+		let addr = listener.local_addr().unwrap().to_string();
+
+		let vol = Arc::new(StubVolume {
+			name: name.to_string(),
+			addr: addr.clone(),
+			blobs: Mutex::new(HashMap::new()),
+			fail_puts: AtomicBool::new(false),
+			corrupt: AtomicBool::new(false),
+			sent: AtomicU64::new(0),
+			hold: Mutex::new(None),
+		});
+
+		self.names.lock().unwrap().insert(addr, name.to_string());
+
+		// A thread for each connection, readers hold several shards open at once.
+		let (stubs, served) = (self.clone(), vol.clone());
+		thread::spawn(move || {
+			for stream in listener.incoming().flatten() {
+				let (stubs, served) = (stubs.clone(), served.clone());
+				thread::spawn(move || stubs.serve(&served, stream));
+			}
+		});
+
+		vol
+	}
+
+	// Has the log show where the record of `key` in `db` points.
+	pub fn watch(&self, db: Arc<dyn IndexStore>, key: &str) {
+		let _ = self.watched.set((db, key.to_string()));
+	}
+
+	// One request per connection, the response closes it.
+	fn serve(&self, vol: &StubVolume, stream: TcpStream) {
+		let mut reader = BufReader::new(stream.try_clone().unwrap());
+
+		let req = match read_request(&mut reader) {
+			Ok(req) => req,
+			Err(_e) => return,
+		};
+
+		let rvolumes = self.watched.get().and_then(|(db, key)| db.get(key)).map(|v| Record::from(v).rvolumes).unwrap_or_default();
+		let names = self.names.lock().unwrap();
+		let on = rvolumes.iter().map(|v| names.get(v).cloned().unwrap_or_default()).collect::<Vec<String>>();
+		drop(names);
+
+		self.log.lock().unwrap().push(format!("{} {} [{}]", req.method, vol.name, on.join(",")));
+
+		if req.method == "PUT" {
+			if let Some(hold) = vol.hold.lock().unwrap().as_ref() {
+				let _ = hold.recv();
+			}
+		}
+
+		let mut blobs = vol.blobs.lock().unwrap();
+
+		let (status, body) = match req.method.as_str() {
+			"PUT" if vol.fail_puts.load(Ordering::SeqCst) => (500, vec![]),
+			"PUT" => {
+				blobs.insert(req.path, req.body);
+				(201, vec![])
+			},
+			"GET" | "HEAD" => match (blobs.get(&req.path), req.range) {
+				(Some(blob), Some((first, last))) if req.method == "GET" => match blob.get(first..=last) {
+					Some(part) => (206, part.to_vec()),
+					None => (416, vec![]),
+				},
+				(Some(blob), _) => {
+					let mut blob = blob.clone();
+					if vol.corrupt.load(Ordering::SeqCst) { blob[0] ^= 1; }
+					(200, blob)
+				},
+				(None, _) => (404, vec![]),
+			},
+			"DELETE" => {
+				blobs.remove(&req.path);
+				(204, vec![])
+			},
+			_ => (405, vec![]),
+		};
+
+		drop(blobs);
+
+		// HEAD gets the length of what GET would send, without the body.
+		let head = format!("HTTP/1.1 {} Stub\r\nContent-Length: {}\r\nConnection: close\r\n\r\n", status, body.len());
+		let mut stream = stream;
+		let _ = stream.write_all(head.as_bytes());
+		if req.method != "HEAD" {
+			vol.sent.fetch_add(body.len() as u64, Ordering::SeqCst);
+			let _ = stream.write_all(&body);
+		}
+	}
+}
+
+fn read_request(r: &mut BufReader<TcpStream>) -> io::Result<StubRequest> {
+	let line = read_line(r)?;
+	let mut parts = line.split(' ');
+	let (method, path) = (parts.next().unwrap_or_default().to_string(), parts.next().unwrap_or_default().to_string());
+
+	let mut length = 0;
+	let mut chunked = false;
+	let mut range = None;
+
+	loop {
+		let line = read_line(r)?;
+		if line.is_empty() { break; }
+
+		let (name, value) = line.split_once(':').unwrap_or((&line, ""));
+		match name.trim().to_ascii_lowercase().as_str() {
+			"content-length" => length = value.trim().parse().unwrap_or(0),
+			"transfer-encoding" => chunked = value.trim().eq_ignore_ascii_case("chunked"),
+			"range" => range = value.trim().strip_prefix("bytes=")
+				.and_then(|r| r.split_once('-'))
+				.and_then(|(first, last)| Some((first.parse().ok()?, last.parse().ok()?))),
+			_ => {},
+		}
+	}
+
+	let mut body = Vec::new();
+
+	if chunked {
+		loop {
+			let size = usize::from_str_radix(read_line(r)?.split(';').next().unwrap_or_default().trim(), 16)
+				.map_err(|e| io::Error::new(io::ErrorKind::InvalidData, e))?;
+
+			if size == 0 {
+				// Trailers, if any, up to the empty line.
+				while !read_line(r)?.is_empty() {}
+				break;
+			}
+
+			let start = body.len();
+			body.resize(start + size, 0);
+			r.read_exact(&mut body[start..])?;
+			read_line(r)?;
+		}
+	} else {
+		body.resize(length, 0);
+		r.read_exact(&mut body)?;
+	}
+
+	Ok(StubRequest { method, path, range, body })
+}
+
+fn read_line(r: &mut BufReader<TcpStream>) -> io::Result<String> {
+	let mut line = String::new();
+	if r.read_line(&mut line)? == 0 {
+		return Err(io::ErrorKind::UnexpectedEof.into());
+	}
+
+	Ok(line.trim_end_matches(['\r', '\n']).to_string())
+}